@@ -1,28 +1,124 @@
 use crate::{
-    http::trace_util::{def_format_headers, def_tracer, TraceConfig, TRACE_ID_HEADER},
+    http::trace_util::{
+        decode_body, def_format_headers, def_record_header_sizes, def_tracer, span_at, status_class, AccessLog, TraceConfig,
+        REQ_HEADER_SIZE_FIELDS, RESP_HEADER_SIZE_FIELDS,
+    },
     tracing::get_trace_id,
-    util::radix32::radix_32,
 };
+pub use crate::http::trace_util::TraceIdEncoding;
 use async_trait::async_trait;
 use http::{Extensions, HeaderMap, HeaderValue};
 use http_body_util::BodyExt;
 use hyper::body::Body;
 use reqwest::{self, Request, Response};
 use reqwest_middleware::{ClientWithMiddleware, Middleware, Next};
-use tracing::{field::Empty, trace, trace_span, Instrument};
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{error, field::Empty, trace, Instrument};
 
 def_tracer!(pub Tracer);
 
+/// Keeps the [`Tracer`] from sending the trace id header on one request, e.g. to a third-party API.
+/// Set it with `client.get(url).with_extension(NoTraceId)`, the request is still traced locally.
+#[derive(Clone, Copy, Debug)]
+pub struct NoTraceId;
+
+impl Tracer {
+    pub fn builder() -> TracerBuilder {
+        TracerBuilder::default()
+    }
+}
+
+/// Sets each option of a [`Tracer`] independently, everything is off until set.
+#[derive(Clone, Default)]
+pub struct TracerBuilder(TraceConfig);
+
+impl TracerBuilder {
+    pub fn log_req_headers(mut self, enabled: bool) -> Self {
+        self.0.log_req_headers = enabled;
+        self
+    }
+    pub fn log_resp_headers(mut self, enabled: bool) -> Self {
+        self.0.log_resp_headers = enabled;
+        self
+    }
+    /// Bodies larger than `max_size` bytes are not logged, 0 disables request body logging.
+    pub fn log_req_body_size(mut self, max_size: u64) -> Self {
+        self.0.log_req_body_size = max_size;
+        self
+    }
+    /// Bodies larger than `max_size` bytes are not logged, 0 disables response body logging.
+    pub fn log_resp_body_size(mut self, max_size: u64) -> Self {
+        self.0.log_resp_body_size = max_size;
+        self
+    }
+    pub fn only_on_error(mut self, enabled: bool) -> Self {
+        self.0.only_on_error = enabled;
+        self
+    }
+    /// With [`only_on_error`](Self::only_on_error), still logs headers of successful requests.
+    pub fn always_log_headers(mut self, enabled: bool) -> Self {
+        self.0.always_log_headers = enabled;
+        self
+    }
+    /// `name` must be a valid header name, it defaults to `X-Trace-Id`.
+    pub fn trace_id_header(mut self, name: &'static str) -> Self {
+        self.0.trace_id_header = name;
+        self
+    }
+    pub fn trace_id_encoding(mut self, encoding: TraceIdEncoding) -> Self {
+        self.0.trace_id_encoding = encoding;
+        self
+    }
+    pub fn access_log(mut self, enabled: bool) -> Self {
+        self.0.access_log = enabled;
+        self
+    }
+    pub fn span_level(mut self, level: tracing::Level) -> Self {
+        self.0.span_level = level;
+        self
+    }
+    pub fn log_header_sizes(mut self, enabled: bool) -> Self {
+        self.0.log_header_sizes = enabled;
+        self
+    }
+    pub fn build(self) -> Tracer {
+        Tracer(self.0)
+    }
+}
+
 #[async_trait]
 impl Middleware for Tracer {
     async fn handle(&self, mut req: Request, extensions: &mut Extensions, next: Next<'_>) -> reqwest_middleware::Result<Response> {
-        let span = trace_span!("send http request", uri=%req.url(), method=%req.method(), status=Empty);
+        let span = span_at!(
+            self.0.span_level,
+            "send http request",
+            uri=%req.url(),
+            method=%req.method(),
+            status=Empty,
+            status_class=Empty,
+            content_type=Empty,
+            req_header_count=Empty,
+            req_header_bytes=Empty,
+            resp_header_count=Empty,
+            resp_header_bytes=Empty,
+        );
         span.id()
+            .filter(|_| extensions.get::<NoTraceId>().is_none())
             .and_then(|id| get_trace_id(&id))
-            .map(|trace_id| req.headers_mut().insert(TRACE_ID_HEADER, HeaderValue::from_str(
-                &format!("{}", radix_32(trace_id))).unwrap()));
+            .map(|trace_id| req.headers_mut().insert(self.0.trace_id_header, HeaderValue::from_str(
+                &self.0.trace_id_encoding.encode(trace_id)).unwrap()));
 
+        if self.0.log_header_sizes {
+            record_header_sizes(&span, req.headers(), REQ_HEADER_SIZE_FIELDS);
+        }
         let req_headers = self.0.log_req_headers.then_some(format_headers(req.headers()));
+        let req_encoding = content_encoding(req.headers()).map(str::to_owned);
         let req_body = (self.0.log_req_body_size > 0)
             .then_some(())
             .and_then(|_| req.body())
@@ -32,27 +128,55 @@ impl Middleware for Tracer {
             .and_then(|body| body.as_bytes())
             .map(|bytes| bytes.to_owned());
 
-        let mut resp = next.run(req, extensions).instrument(span.clone()).await?;
+        let access_log = self.0.access_log.then(|| AccessLog::start(
+            req.method().as_str(),
+            req.url().path(),
+            req.body().and_then(|body| body.as_bytes()).map(|bytes| bytes.len() as u64),
+        ));
+
+        let result = next.run(req, extensions).instrument(span.clone()).await;
+        if let Some(access_log) = &access_log {
+            span.in_scope(|| access_log.log(
+                result.as_ref().ok().map(|resp| resp.status().as_u16()),
+                result.as_ref().ok().and_then(Response::content_length),
+            ));
+        }
+        let mut resp = result
+            .inspect_err(|e| span.in_scope(|| error!("SEND_HTTP_REQUEST_ERROR: {}", e)))?;
         span.record("status", resp.status().as_u16());
+        span.record("status_class", status_class(resp.status().as_u16()));
+        if let Some(content_type) = resp.headers().get(http::header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+            span.record("content_type", content_type);
+        }
+        if self.0.log_header_sizes {
+            record_header_sizes(&span, resp.headers(), RESP_HEADER_SIZE_FIELDS);
+        }
 
         let should_log = resp.status().is_client_error() || !self.0.only_on_error;
         let should_log_headers = should_log || self.0.always_log_headers;
-        if req_headers.is_some() && should_log_headers {
-            trace!(req_headers=req_headers.unwrap())
+        if let Some(req_headers) = req_headers.filter(|_| should_log_headers) {
+            trace!(req_headers=req_headers)
         }
-        if req_body.is_some() && should_log {
-            trace!(req_body=%String::from_utf8_lossy(&req_body.unwrap()))
+        if let Some(req_body) = req_body.filter(|_| should_log) {
+            match decode_body(req_encoding.as_deref(), &req_body, self.0.log_req_body_size) {
+                Ok(body) => trace!(req_body=%String::from_utf8_lossy(&body)),
+                Err(e) => trace!("request body not logged: {}", e),
+            }
         }
         if self.0.log_resp_headers && should_log_headers {
             trace!(resp_headers=format_headers(resp.headers()))
         }
         if self.0.log_resp_body_size > 0 && should_log &&
             resp.content_length().map(|size| size <= self.0.log_resp_body_size).unwrap_or(false) {
+            let encoding = content_encoding(resp.headers()).map(str::to_owned);
             let (parts, body) = http::Response::from(resp).into_parts();
             let body_bytes = body.collect()
                 .await
                 .map(|buf| buf.to_bytes())?;
-            trace!(resp_body=%String::from_utf8_lossy(&body_bytes));
+            match decode_body(encoding.as_deref(), &body_bytes, self.0.log_resp_body_size) {
+                Ok(body) => trace!(resp_body=%String::from_utf8_lossy(&body)),
+                Err(e) => trace!("response body not logged: {}", e),
+            }
             let http_resp = http::Response::from_parts(parts, body_bytes);
             resp = Response::from(http_resp);
         }
@@ -61,12 +185,364 @@ impl Middleware for Tracer {
 }
 
 def_format_headers!(HeaderMap);
+def_record_header_sizes!(HeaderMap);
+
+fn content_encoding(headers: &HeaderMap) -> Option<&str> {
+    headers.get(http::header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+}
 
 pub type Client = ClientWithMiddleware;
 pub use reqwest_middleware::ClientBuilder;
 
 pub fn default_with_trace(mw: Tracer) -> Client {
+    builder(mw).build()
+}
+
+/// Returns a builder with the [`Tracer`] installed, middlewares added to it run inside the tracer.
+pub fn builder(mw: Tracer) -> ClientBuilder {
     ClientBuilder::new(reqwest::Client::new())
         .with(mw)
-        .build()
+}
+
+/// One configuration for every outgoing client of a service, see [`shared_client`].
+#[derive(Clone)]
+pub struct SharedClientConfig {
+    pub tracer: Tracer,
+    /// Total time allowed per request, including reading the body.
+    pub timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    /// Sent with every request unless the request sets them itself.
+    pub default_headers: HeaderMap,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+}
+
+impl Default for SharedClientConfig {
+    fn default() -> Self {
+        Self {
+            tracer: Tracer::trace_only(),
+            timeout: Some(Duration::from_secs(30)),
+            connect_timeout: Some(Duration::from_secs(5)),
+            default_headers: HeaderMap::new(),
+            circuit_breaker: None,
+        }
+    }
+}
+
+/// Builds the client a service shares between its own calls and `lark_api::Client::new`,
+/// so all of them are traced and time out alike. Retrying Lark calls is configured on the Lark client itself
+/// with `with_retry`, which knows which of its errors are transient.
+pub fn shared_client(config: &SharedClientConfig) -> Result<Client, reqwest::Error> {
+    let mut client = reqwest::Client::builder();
+    if let Some(timeout) = config.timeout {
+        client = client.timeout(timeout);
+    }
+    if let Some(timeout) = config.connect_timeout {
+        client = client.connect_timeout(timeout);
+    }
+    let mut builder = ClientBuilder::new(client.build()?);
+    if !config.default_headers.is_empty() {
+        builder = builder.with_default_headers(config.default_headers.clone());
+    }
+    builder = builder.with(config.tracer.clone());
+    if let Some(circuit_breaker) = config.circuit_breaker {
+        builder = builder.with_circuit_breaker(circuit_breaker);
+    }
+    Ok(builder.build())
+}
+
+pub trait ClientBuilderExt {
+    /// Adds a [`CircuitBreaker`]. Add it after the [`Tracer`] so fast-failed requests are still traced.
+    fn with_circuit_breaker(self, config: CircuitBreakerConfig) -> Self;
+    /// Adds [`DefaultHeaders`]. Add it before the [`Tracer`] so the traced request headers include them.
+    fn with_default_headers(self, headers: HeaderMap) -> Self;
+    /// Counts requests passing through into `stats`, keep a clone of the `Arc` to read them.
+    fn with_request_stats(self, stats: Arc<RequestStats>) -> Self;
+}
+
+impl ClientBuilderExt for ClientBuilder {
+    fn with_circuit_breaker(self, config: CircuitBreakerConfig) -> Self {
+        self.with(CircuitBreaker::new(config))
+    }
+
+    fn with_default_headers(self, headers: HeaderMap) -> Self {
+        self.with(DefaultHeaders(headers))
+    }
+
+    fn with_request_stats(self, stats: Arc<RequestStats>) -> Self {
+        self.with(RequestCounter(stats))
+    }
+}
+
+#[derive(Default)]
+pub struct RequestStats {
+    in_flight: AtomicUsize,
+    total: AtomicU64,
+}
+
+impl RequestStats {
+    /// Requests sent and not yet answered.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Requests sent since the client was built.
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+struct RequestCounter(Arc<RequestStats>);
+
+// decrements on drop so cancelled requests are not counted as in flight forever
+struct InFlight<'a>(&'a RequestStats);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl Middleware for RequestCounter {
+    async fn handle(&self, req: Request, extensions: &mut Extensions, next: Next<'_>) -> reqwest_middleware::Result<Response> {
+        self.0.total.fetch_add(1, Ordering::Relaxed);
+        self.0.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _in_flight = InFlight(&self.0);
+        next.run(req, extensions).await
+    }
+}
+
+/// Inserts each header that the request does not already set.
+#[derive(Clone)]
+pub struct DefaultHeaders(pub HeaderMap);
+
+#[async_trait]
+impl Middleware for DefaultHeaders {
+    async fn handle(&self, mut req: Request, extensions: &mut Extensions, next: Next<'_>) -> reqwest_middleware::Result<Response> {
+        let headers = req.headers_mut();
+        for name in self.0.keys() {
+            if !headers.contains_key(name) {
+                for value in self.0.get_all(name) {
+                    headers.append(name, value.clone());
+                }
+            }
+        }
+        next.run(req, extensions).await
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: u32,
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("circuit breaker is open")]
+pub struct CircuitOpenError;
+
+enum CircuitState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // a probe is in flight; `until` bounds how long a lost probe can keep the circuit blocked
+    HalfOpen { until: Instant },
+}
+
+/// Fails fast with [`CircuitOpenError`] after `failure_threshold` consecutive failures
+/// (transport errors or 5xx responses), then lets a single probe through once `cooldown` has elapsed.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Arc<Mutex<CircuitState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Arc::new(Mutex::new(CircuitState::Closed { failures: 0 })),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { until } | CircuitState::HalfOpen { until } if until <= Instant::now() => {
+                *state = CircuitState::HalfOpen { until: Instant::now() + self.config.cooldown };
+                true
+            }
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => false,
+        }
+    }
+
+    fn on_result(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        *state = match (&*state, success) {
+            (_, true) => CircuitState::Closed { failures: 0 },
+            (CircuitState::Closed { failures }, false) if failures + 1 < self.config.failure_threshold => {
+                CircuitState::Closed { failures: failures + 1 }
+            }
+            (_, false) => CircuitState::Open { until: Instant::now() + self.config.cooldown },
+        };
+    }
+}
+
+#[async_trait]
+impl Middleware for CircuitBreaker {
+    async fn handle(&self, req: Request, extensions: &mut Extensions, next: Next<'_>) -> reqwest_middleware::Result<Response> {
+        if !self.try_acquire() {
+            return Err(reqwest_middleware::Error::middleware(CircuitOpenError));
+        }
+        let result = next.run(req, extensions).await;
+        self.on_result(result.as_ref().is_ok_and(|resp| !resp.status().is_server_error()));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::trace_util::TRACE_ID_HEADER;
+    use http::header::ACCEPT;
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    struct Capture(Arc<Mutex<Option<HeaderMap>>>);
+
+    #[async_trait]
+    impl Middleware for Capture {
+        async fn handle(&self, req: Request, _: &mut Extensions, _: Next<'_>) -> reqwest_middleware::Result<Response> {
+            *self.0.lock().unwrap() = Some(req.headers().clone());
+            Ok(Response::from(http::Response::new("")))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_headers_overridden_by_request() {
+        let mut defaults = HeaderMap::new();
+        defaults.insert("x-service-name", HeaderValue::from_static("default"));
+        defaults.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        let captured = Arc::new(Mutex::new(None));
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_default_headers(defaults)
+            .with(Capture(captured.clone()))
+            .build();
+
+        client.get("http://localhost/")
+            .header("x-service-name", "override")
+            .send()
+            .await
+            .unwrap();
+
+        let headers = captured.lock().unwrap().take().unwrap();
+        assert_eq!(headers.get_all("x-service-name").iter().collect::<Vec<_>>(), ["override"]);
+        assert_eq!(headers[ACCEPT], "application/json");
+    }
+
+    // answers with the next queued status, counting the requests that got through
+    struct Upstream(Arc<Mutex<(Vec<u16>, usize)>>);
+
+    #[async_trait]
+    impl Middleware for Upstream {
+        async fn handle(&self, _: Request, _: &mut Extensions, _: Next<'_>) -> reqwest_middleware::Result<Response> {
+            let mut upstream = self.0.lock().unwrap();
+            upstream.1 += 1;
+            let status = upstream.0.remove(0);
+            let resp = http::Response::builder().status(status).body("").unwrap();
+            Ok(Response::from(resp))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_trips_and_recovers() {
+        let cooldown = Duration::from_millis(50);
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig { failure_threshold: 2, cooldown });
+        let upstream = Arc::new(Mutex::new((vec![500, 500, 200, 500, 200], 0)));
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(breaker.clone())
+            .with(Upstream(upstream.clone()))
+            .build();
+        let call = || async { client.get("http://localhost/").send().await };
+        let is_open = |result: &reqwest_middleware::Result<Response>| matches!(
+            result, Err(reqwest_middleware::Error::Middleware(e)) if e.is::<CircuitOpenError>()
+        );
+
+        assert_eq!(call().await.unwrap().status(), 500);
+        assert_eq!(call().await.unwrap().status(), 500);
+        // open: fails fast without reaching upstream
+        assert!(is_open(&call().await));
+        assert_eq!(upstream.lock().unwrap().1, 2);
+
+        tokio::time::sleep(cooldown).await;
+        // the probe succeeds and closes the circuit with the failure count reset
+        assert_eq!(call().await.unwrap().status(), 200);
+        assert_eq!(call().await.unwrap().status(), 500);
+        assert_eq!(call().await.unwrap().status(), 200);
+        assert_eq!(upstream.lock().unwrap().1, 5);
+    }
+
+    #[test]
+    fn test_circuit_breaker_single_half_open_probe() {
+        let cooldown = Duration::from_millis(20);
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig { failure_threshold: 1, cooldown });
+        breaker.on_result(false);
+        assert!(!breaker.try_acquire());
+
+        std::thread::sleep(cooldown);
+        assert!(breaker.try_acquire());
+        // the probe is in flight, everyone else still fails fast
+        assert!(!breaker.try_acquire());
+
+        // a failed probe opens the circuit for another cooldown
+        breaker.on_result(false);
+        assert!(!breaker.try_acquire());
+        std::thread::sleep(cooldown);
+        assert!(breaker.try_acquire());
+        breaker.on_result(true);
+        assert!(breaker.try_acquire());
+        assert!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn test_tracer_builder_sets_fields_independently() {
+        let tracer = Tracer::builder()
+            .log_req_headers(true)
+            .log_resp_body_size(1024)
+            .only_on_error(true)
+            .build();
+
+        assert!(tracer.0.log_req_headers);
+        assert!(!tracer.0.log_resp_headers);
+        assert_eq!(tracer.0.log_req_body_size, 0);
+        assert_eq!(tracer.0.log_resp_body_size, 1024);
+        assert!(tracer.0.only_on_error);
+        assert!(!tracer.0.always_log_headers);
+    }
+
+    #[tokio::test]
+    async fn test_no_trace_id_skips_header() {
+        let captured = Arc::new(Mutex::new(None));
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(Tracer::trace_only())
+            .with(Capture(captured.clone()))
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::tracing::CloudNativeLayer(Default::default(), std::io::sink));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        client.get("http://localhost/").send().await.unwrap();
+        assert!(captured.lock().unwrap().take().unwrap().contains_key(TRACE_ID_HEADER));
+        client.get("http://localhost/").with_extension(NoTraceId).send().await.unwrap();
+        assert!(!captured.lock().unwrap().take().unwrap().contains_key(TRACE_ID_HEADER));
+    }
 }
@@ -1,6 +1,9 @@
 #[cfg(any(
     feature = "http-jwt",
-    feature = "http-server-tracer"
+    feature = "http-server-tracer",
+    feature = "http-health",
+    feature = "http-cors",
+    feature = "http-timeout"
 ))]
 pub mod server;
 
@@ -9,3 +12,7 @@ pub mod client;
 
 #[cfg(any(feature = "http-server-tracer", feature = "http-client"))]
 pub(crate) mod trace_util;
+
+/// Default header carrying the trace id, see `trace_id_header` on the tracers.
+#[cfg(any(feature = "http-server-tracer", feature = "http-client", feature = "http-cors"))]
+pub(crate) const TRACE_ID_HEADER: &str = "X-Trace-Id";
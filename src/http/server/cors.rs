@@ -0,0 +1,52 @@
+use crate::http::TRACE_ID_HEADER;
+use actix_cors::Cors;
+use actix_web::http::{
+    header::{self, HeaderName},
+    Method,
+};
+
+#[derive(Clone)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API, e.g. `https://app.example.com`. Empty allows none.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<HeaderName>,
+    /// Headers readable by browser scripts, the trace id header is always exposed.
+    pub expose_headers: Vec<HeaderName>,
+    /// The tracer's trace id header, `X-Trace-Id` unless changed with `Tracer::trace_id_header`.
+    /// [`standard_app`](super::stack::standard_app) fills it in from its tracer.
+    pub trace_id_header: &'static str,
+    pub supports_credentials: bool,
+    /// Seconds browsers may cache preflight responses.
+    pub max_age: Option<usize>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec![Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE],
+            allowed_headers: vec![header::AUTHORIZATION, header::ACCEPT, header::CONTENT_TYPE],
+            expose_headers: Vec::new(),
+            trace_id_header: TRACE_ID_HEADER,
+            supports_credentials: false,
+            max_age: Some(3600),
+        }
+    }
+}
+
+/// Builds the CORS middleware. It must wrap the JWT verifier, i.e. call `.wrap(cors(..))` after
+/// `.wrap(JwtVerifier)`, otherwise preflight `OPTIONS` requests carry no token and are rejected.
+pub fn cors(config: &CorsConfig) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(config.allowed_methods.iter().cloned())
+        .allowed_headers(config.allowed_headers.iter().cloned())
+        .expose_headers(config.expose_headers.iter().cloned().chain(HeaderName::try_from(config.trace_id_header).ok()));
+    for origin in &config.allowed_origins {
+        cors = cors.allowed_origin(origin);
+    }
+    if config.supports_credentials {
+        cors = cors.supports_credentials();
+    }
+    cors.max_age(config.max_age)
+}
@@ -0,0 +1,23 @@
+use actix_web::{web, HttpResponse};
+use std::sync::Arc;
+
+pub type ReadinessCheck = Arc<dyn Fn() -> bool + Send + Sync>;
+
+/// Kubernetes probe routes, `/healthz` always answers 200 and `/readyz` answers 503 while `ready` returns false.
+///
+/// Use with `App::configure(health(ready))`.
+pub fn health(ready: ReadinessCheck) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg.route("/healthz", web::get().to(|| async { HttpResponse::Ok().finish() }))
+            .route("/readyz", web::get().to(move || {
+                let ready = ready.clone();
+                async move {
+                    if ready() {
+                        HttpResponse::Ok().finish()
+                    } else {
+                        HttpResponse::ServiceUnavailable().finish()
+                    }
+                }
+            }));
+    }
+}
@@ -2,19 +2,23 @@ use actix_web::{
     body::MessageBody,
     dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
     error::InternalError,
-    http::{header::{HeaderMap, ToStrError}, StatusCode},
-    Error, FromRequest, HttpMessage, HttpRequest, ResponseError,
+    http::{header::{self, HeaderMap, ToStrError}, StatusCode},
+    cookie::{time, Cookie, SameSite},
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
 };
+use crate::util::{id_gen::gen_id, radix32::radix_32};
 use async_trait::async_trait;
-use jwt::{AlgorithmType, SignWithKey as _, SigningAlgorithm, ToBase64, Token, VerifyWithKey as _, VerifyingAlgorithm};
+use hmac::{Hmac, Mac as _};
+use jwt::{AlgorithmType, FromBase64, SignWithKey as _, SigningAlgorithm, ToBase64, Token, VerifyWithKey as _, VerifyingAlgorithm};
 use pin_project::pin_project;
 use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha256;
 use std::{
     future::{ready, Future, Ready},
     ops::Deref,
     pin::Pin,
     rc::Rc,
-    sync::Arc,
+    sync::{Arc, RwLock},
     task::{Context, Poll},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
@@ -85,37 +89,151 @@ pub enum VerifierError {
     SerdeJsonError(#[from] serde_json::error::Error),
     #[error("token is expired")]
     Expired,
+    #[error("token is not valid yet")]
+    NotYetValid,
+    #[error("token lacks the '{0}' claim")]
+    MissingClaim(&'static str),
+    #[error("token audience mismatch")]
+    InvalidAudience,
+    #[error("token issuer mismatch")]
+    InvalidIssuer,
+    /// A valid token the request is not permitted with, see [`Verifier::with_forbidden_on_mismatch`].
+    #[error("{0}")]
+    Forbidden(Box<VerifierError>),
 }
 
 impl VerifierError {
+    /// A short stable name of the variant, e.g. `expired` or `invalid_signature`, for metric labels and
+    /// log fields. [`VerifierError::Forbidden`] takes the name of the error it wraps.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            VerifierError::NoVerifier => "no_verifier",
+            VerifierError::NotProvided => "missing",
+            VerifierError::NotValidStr(_) => "not_valid_str",
+            VerifierError::InvalidTokenType => "invalid_token_type",
+            VerifierError::JwtError(jwt::Error::InvalidSignature | jwt::Error::RustCryptoMac(_)) => "invalid_signature",
+            VerifierError::JwtError(_) => "malformed",
+            VerifierError::SerdeJsonError(_) => "invalid_claims",
+            VerifierError::Expired => "expired",
+            VerifierError::NotYetValid => "not_yet_valid",
+            VerifierError::MissingClaim(_) => "missing_claim",
+            VerifierError::InvalidAudience => "invalid_audience",
+            VerifierError::InvalidIssuer => "invalid_issuer",
+            VerifierError::Forbidden(e) => e.reason(),
+        }
+    }
+
     fn to_error(&self) -> Error {
-        InternalError::new(
+        InternalError::from_response(
             format!("{}", self),
-            self.status_code(),
+            self.error_response(),
         ).into()
     }
+
+    // the header or the token in it doesn't parse, answered 400 as before challenges were added, while
+    // tokens that parse but fail verification are 401
+    fn is_malformed(&self) -> bool {
+        use jwt::Error as E;
+
+        match self {
+            VerifierError::NotValidStr(_) | VerifierError::InvalidTokenType | VerifierError::SerdeJsonError(_) => true,
+            VerifierError::JwtError(e) => matches!(
+                e,
+                E::Base64(_) | E::Format | E::Json(_) | E::Utf8(_) | E::NoHeaderComponent | E::NoClaimsComponent
+                    | E::NoSignatureComponent | E::TooManyComponents
+            ),
+            _ => false,
+        }
+    }
+
+    // RFC 6750 challenge, with an error code only when a token was presented
+    fn www_authenticate(&self) -> Option<&'static str> {
+        match self {
+            VerifierError::NoVerifier | VerifierError::Forbidden(_) => None,
+            VerifierError::NotProvided => Some("Bearer"),
+            e if e.is_malformed() => Some("Bearer error=\"invalid_request\""),
+            VerifierError::Expired => Some("Bearer error=\"invalid_token\", error_description=\"token is expired\""),
+            _ => Some("Bearer error=\"invalid_token\""),
+        }
+    }
 }
 
 impl ResponseError for VerifierError {
     fn status_code(&self) -> StatusCode {
         match self {
             VerifierError::NoVerifier => StatusCode::INTERNAL_SERVER_ERROR,
-            _ => StatusCode::BAD_REQUEST,
+            e if e.is_malformed() => StatusCode::BAD_REQUEST,
+            VerifierError::Forbidden(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut resp = HttpResponse::build(self.status_code());
+        resp.insert_header(header::ContentType::plaintext());
+        if let Some(challenge) = self.www_authenticate() {
+            resp.insert_header((header::WWW_AUTHENTICATE, challenge));
         }
+        resp.body(self.to_string())
     }
 }
 
-struct ClaimsValue(Result<serde_json::Value, VerifierError>);
+struct ClaimsValue(Result<Arc<serde_json::Value>, VerifierError>);
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum VerifierMode {
     MustSuccess,
     AllowFailed,
 }
+/// Returns the raw token of a request, `None` when it carries no token.
+pub type TokenExtractor = Arc<dyn Fn(&HttpRequest) -> Option<String> + Send + Sync>;
+/// Returns the current time for expiry checks and stamps, [`SystemTime::now`] unless replaced, e.g. by a
+/// frozen clock in tests.
+pub type Clock = Arc<dyn Fn() -> SystemTime + Send + Sync>;
+
 #[derive(Clone)]
 pub struct Verifier<A> {
     algorithm: A,
     mode: VerifierMode,
+    extractor: Option<TokenExtractor>,
+    header_name: String,
+    scheme: String,
+    cookie: Option<String>,
+    policy: ClaimsPolicy,
+    forbidden_on_mismatch: bool,
+    #[cfg(feature = "http-jwt-metrics")]
+    outcome_counter: bool,
+    clock: Clock,
+}
+
+/// Which claims a [`Verifier`] checks, each independently. The default only enforces expiry.
+#[derive(Clone)]
+pub struct ClaimsPolicy {
+    /// Rejects tokens past the expiry [`Signer`] writes into the header.
+    pub expiry: bool,
+    /// Requires the standard `exp` claim, in seconds since the epoch, to be in the future.
+    pub exp: bool,
+    /// Rejects tokens whose `nbf` claim, if any, is in the future.
+    pub nbf: bool,
+    /// Requires the `iss` claim to equal this.
+    pub issuer: Option<String>,
+    /// Requires the `aud` claim, a string or an array, to contain this.
+    pub audience: Option<String>,
+    /// Requires a string `jti` claim, e.g. for revocation lists.
+    pub jti: bool,
+}
+
+impl Default for ClaimsPolicy {
+    fn default() -> Self {
+        Self {
+            expiry: true,
+            exp: false,
+            nbf: false,
+            issuer: None,
+            audience: None,
+            jti: false,
+        }
+    }
 }
 
 impl<A> Verifier<A>
@@ -123,10 +241,199 @@ where
     A: VerifyingAlgorithm,
 {
     pub fn new(algorithm: A, mode: VerifierMode) -> Self {
-        Self { algorithm, mode }
+        Self {
+            algorithm,
+            mode,
+            extractor: None,
+            header_name: "Authorization".to_string(),
+            scheme: "Bearer".to_string(),
+            cookie: None,
+            policy: ClaimsPolicy::default(),
+            forbidden_on_mismatch: false,
+            #[cfg(feature = "http-jwt-metrics")]
+            outcome_counter: false,
+            clock: Arc::new(SystemTime::now),
+        }
+    }
+
+    /// Reads the token from `header_name` instead of `Authorization`.
+    pub fn with_header_name(self, header_name: &str) -> Self {
+        Self { header_name: header_name.to_string(), ..self }
+    }
+
+    /// Expects `<scheme> <token>` in the header instead of `Bearer <token>`, an empty scheme takes the whole value.
+    pub fn with_scheme(self, scheme: &str) -> Self {
+        Self { scheme: scheme.to_string(), ..self }
+    }
+
+    /// Falls back to the cookie `name` when the token header is absent.
+    pub fn with_cookie(self, name: &str) -> Self {
+        Self { cookie: Some(name.to_string()), ..self }
+    }
+
+    /// Requires the `aud` claim, a string or an array of strings, to contain `audience`.
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.policy.audience = Some(audience.to_string());
+        self
+    }
+
+    /// Requires the `iss` claim to equal `issuer`.
+    pub fn with_issuer(mut self, issuer: &str) -> Self {
+        self.policy.issuer = Some(issuer.to_string());
+        self
+    }
+
+    /// Replaces every claim check, including those set by [`with_audience`](Self::with_audience) and
+    /// [`with_issuer`](Self::with_issuer).
+    pub fn with_claims_policy(self, policy: ClaimsPolicy) -> Self {
+        Self { policy, ..self }
+    }
+
+    /// Answers audience and issuer mismatches with 403 instead of 401, as [`VerifierError::Forbidden`]: the
+    /// token is valid but not meant for this service. Missing, malformed and expired tokens stay 401.
+    pub fn with_forbidden_on_mismatch(self, enabled: bool) -> Self {
+        Self { forbidden_on_mismatch: enabled, ..self }
+    }
+
+    /// Counts every verification into the `jwt_verifications_total` counter, labelled `outcome` with
+    /// `success` or `failure` and, for failures, `reason` with [`VerifierError::reason`].
+    #[cfg(feature = "http-jwt-metrics")]
+    pub fn with_outcome_counter(self, enabled: bool) -> Self {
+        Self { outcome_counter: enabled, ..self }
+    }
+
+    /// Replaces the built-in header and cookie extraction with `extractor`.
+    pub fn with_extractor(self, extractor: TokenExtractor) -> Self {
+        Self { extractor: Some(extractor), ..self }
+    }
+
+    /// Checks `expired_at`, `exp` and `nbf` against `clock` instead of the system clock.
+    pub fn with_clock(self, clock: Clock) -> Self {
+        Self { clock, ..self }
+    }
+
+    pub fn mode(&self) -> VerifierMode {
+        self.mode
+    }
+
+    pub fn algorithm_type(&self) -> AlgorithmType {
+        self.algorithm.algorithm_type()
+    }
+
+    fn verify(&self, req: &HttpRequest) -> Result<serde_json::Value, VerifierError> {
+        let result = self.verify_request(req);
+        #[cfg(feature = "http-jwt-metrics")]
+        if self.outcome_counter {
+            match &result {
+                Ok(_) => metrics::counter!("jwt_verifications_total", "outcome" => "success").increment(1),
+                Err(e) => metrics::counter!("jwt_verifications_total", "outcome" => "failure", "reason" => e.reason())
+                    .increment(1),
+            }
+        }
+        result
+    }
+
+    fn verify_request(&self, req: &HttpRequest) -> Result<serde_json::Value, VerifierError> {
+        let token = match &self.extractor {
+            Some(extractor) => extractor(req).ok_or(VerifierError::NotProvided)?,
+            None => self.token(req)?,
+        };
+
+        self.verify_token(&token)
+            .inspect_err(|e| {
+                if tracing::enabled!(tracing::Level::TRACE) {
+                    let sub = decode_unverified(&token).ok()
+                        .and_then(|(_, claims)| claims.get("sub").cloned());
+                    tracing::trace!(error=%e, claimed_sub=?sub, "jwt rejected");
+                }
+            })
+            .map_err(|e| match e {
+                VerifierError::InvalidAudience | VerifierError::InvalidIssuer if self.forbidden_on_mismatch => {
+                    VerifierError::Forbidden(Box::new(e))
+                }
+                e => e,
+            })
+    }
+
+    fn verify_token(&self, token: &str) -> Result<serde_json::Value, VerifierError> {
+        let token: jwt::Token<JwtHeader, serde_json::Value, jwt::Verified> =
+            token.verify_with_key(&self.algorithm)?;
+        let (header, claims) = token.into();
+        if self.policy.expiry && header.expired_at.lt(&(self.clock)()) {
+            return Err(VerifierError::Expired);
+        }
+        self.check_claims(&claims)?;
+        Ok(claims)
+    }
+
+    fn token(&self, req: &HttpRequest) -> Result<String, VerifierError> {
+        if let Some(token) = header_token(req.headers(), &self.header_name, &self.scheme)? {
+            return Ok(token.to_owned());
+        }
+        self.cookie.as_ref()
+            .and_then(|name| req.cookie(name))
+            .map(|cookie| cookie.value().to_owned())
+            .ok_or(VerifierError::NotProvided)
+    }
+
+    fn check_claims(&self, claims: &serde_json::Value) -> Result<(), VerifierError> {
+        let policy = &self.policy;
+        let now = (self.clock)().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if policy.exp {
+            match claims.get("exp").and_then(serde_json::Value::as_u64) {
+                Some(exp) if exp > now => {}
+                Some(_) => return Err(VerifierError::Expired),
+                None => return Err(VerifierError::MissingClaim("exp")),
+            }
+        }
+        if policy.nbf && claims.get("nbf").and_then(serde_json::Value::as_u64).is_some_and(|nbf| nbf > now) {
+            return Err(VerifierError::NotYetValid);
+        }
+        if policy.jti && !claims.get("jti").is_some_and(serde_json::Value::is_string) {
+            return Err(VerifierError::MissingClaim("jti"));
+        }
+        if let Some(issuer) = &policy.issuer {
+            if claims.get("iss").and_then(serde_json::Value::as_str) != Some(issuer) {
+                return Err(VerifierError::InvalidIssuer);
+            }
+        }
+        if let Some(audience) = &policy.audience {
+            let matched = match claims.get("aud") {
+                Some(serde_json::Value::String(aud)) => aud == audience,
+                Some(serde_json::Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matched {
+                return Err(VerifierError::InvalidAudience);
+            }
+        }
+        Ok(())
     }
 }
 
+/// Decodes a token's header and claims WITHOUT checking its signature or expiry.
+///
+/// The result is UNTRUSTED, anyone can forge it. Only use it for debugging or logging,
+/// never for authorization, which must go through [`Verifier`].
+pub fn decode_unverified(token: &str) -> Result<(JwtHeader, serde_json::Value), VerifierError> {
+    let token: jwt::Token<JwtHeader, serde_json::Value, jwt::Unverified> = Token::parse_unverified(token)?;
+    Ok(token.into())
+}
+
+fn header_token<'a>(headers: &'a HeaderMap, name: &str, scheme: &str) -> Result<Option<&'a str>, VerifierError> {
+    let Some(value) = headers.get(name) else {
+        return Ok(None);
+    };
+    let value = value.to_str()?;
+    if scheme.is_empty() {
+        return Ok(Some(value));
+    }
+    value.strip_prefix(scheme)
+        .and_then(|token| token.strip_prefix(' '))
+        .map(Some)
+        .ok_or(VerifierError::InvalidTokenType)
+}
+
 impl<A, S, B> Transform<S, ServiceRequest> for Verifier<A>
 where
     S: Service<ServiceRequest, Response=ServiceResponse<B>, Error=Error>,
@@ -141,17 +448,16 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(VerifierMiddleware {
-            algorithm: self.algorithm.clone(),
+            verifier: Rc::new(self.clone()),
             service: Rc::new(service),
-            mode: self.mode,
         }))
     }
 }
 
 pub struct VerifierMiddleware<A, S> {
-    algorithm: A,
+    // shared with each request's future, cloning the verifier itself would copy its strings per request
+    verifier: Rc<Verifier<A>>,
     service: Rc<S>,
-    mode: VerifierMode,
 }
 
 impl<A, S, B> Service<ServiceRequest> for VerifierMiddleware<A, S>
@@ -168,11 +474,10 @@ where
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
         VerifierFuture {
-            algorithm: self.algorithm.clone(),
+            verifier: self.verifier.clone(),
             req: Some(req),
             service: self.service.clone(),
             future: None,
-            mode: self.mode,
         }
     }
 }
@@ -182,12 +487,11 @@ pub struct VerifierFuture<A, S>
 where
     S: Service<ServiceRequest>,
 {
-    algorithm: A,
+    verifier: Rc<Verifier<A>>,
     req: Option<ServiceRequest>,
     service: Rc<S>,
     #[pin]
     future: Option<S::Future>,
-    mode: VerifierMode,
 }
 
 impl<A, S, B> Future for VerifierFuture<A, S>
@@ -199,32 +503,16 @@ where
     type Output = Result<ServiceResponse<B>, Error>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        fn verify(headers: &HeaderMap, key: &impl VerifyingAlgorithm) -> Result<serde_json::Value, VerifierError> {
-            let token = headers.get("Authorization")
-                .ok_or(VerifierError::NotProvided)?
-                .to_str()?
-                .strip_prefix("Bearer ")
-                .ok_or(VerifierError::InvalidTokenType)?;
-
-            let token: jwt::Token<JwtHeader, serde_json::Value, jwt::Verified> =
-                token.verify_with_key(key)?;
-            let (header, claims) = token.into();
-            if header.expired_at.lt(&SystemTime::now()) {
-                return Err(VerifierError::Expired);
-            }
-            Ok(claims)
-        }
-
         let mut this = self.project();
         if this.future.is_some() {
             return this.future.as_pin_mut().unwrap().poll(cx);
         }
-        match verify(this.req.as_ref().unwrap().headers(), this.algorithm) {
+        match this.verifier.verify(this.req.as_ref().unwrap().request()) {
             Ok(v) => {
-                this.req.as_mut().unwrap().extensions_mut().insert(ClaimsValue(Ok(v)));
+                this.req.as_mut().unwrap().extensions_mut().insert(ClaimsValue(Ok(Arc::new(v))));
             }
             Err(e) => {
-                if matches!(this.mode, VerifierMode::MustSuccess) {
+                if matches!(this.verifier.mode, VerifierMode::MustSuccess) {
                     return Poll::Ready(Err(e.into()));
                 }
                 this.req.as_mut().unwrap().extensions_mut().insert(ClaimsValue(Err(e)));
@@ -261,7 +549,7 @@ where
             req.extensions().get::<ClaimsValue>()
                 .ok_or(VerifierError::NoVerifier)?
                 .0.as_ref().map_err(|e| { e.to_error() })
-                .and_then(|v| T::deserialize(v)
+                .and_then(|v| T::deserialize(&**v)
                     .map_err(VerifierError::from)
                     .map_err(|e| e.into()))
                 .map(|t| Jwt(t))
@@ -270,10 +558,158 @@ where
     }
 }
 
+/// The verified claims as decoded, for handlers reading dynamic fields or forwarding them as a whole.
+pub struct RawClaims(pub Arc<serde_json::Value>);
+impl Deref for RawClaims {
+    type Target = serde_json::Value;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl FromRequest for RawClaims {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<ClaimsValue>()
+            .ok_or_else(|| VerifierError::NoVerifier.to_error())
+            .and_then(|claims| claims.0.as_ref().map_err(|e| e.to_error()))
+            .map(|v| RawClaims(v.clone())))
+    }
+}
+
+/// The verified token's `sub` claim, for handlers that only need the user id. Rejects tokens without a
+/// string `sub` as [`VerifierError::MissingClaim`].
+pub struct Subject(pub String);
+impl Deref for Subject {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl FromRequest for Subject {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<ClaimsValue>()
+            .ok_or_else(|| VerifierError::NoVerifier.to_error())
+            .and_then(|claims| claims.0.as_ref().map_err(|e| e.to_error()))
+            .and_then(|v| v.get("sub")
+                .and_then(serde_json::Value::as_str)
+                .map(|sub| Subject(sub.to_owned()))
+                .ok_or_else(|| VerifierError::MissingClaim("sub").to_error())))
+    }
+}
+
+/// A key that can be replaced at runtime, e.g. from a management endpoint, to rotate secrets without rebuilding
+/// the app. Clones share the key, so hand one to the [`Signer`] and [`Verifier`] and keep one to [`replace`](Self::replace) it.
+///
+/// Each signature is made or checked with a single key. Keep the algorithm type when replacing, as the token
+/// header is written before signing.
+pub struct SwappableKey<A>(Arc<RwLock<Arc<A>>>);
+
+impl<A> Clone for SwappableKey<A> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<A> SwappableKey<A> {
+    pub fn new(key: A) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(key))))
+    }
+
+    pub fn replace(&self, key: A) {
+        *self.0.write().unwrap() = Arc::new(key);
+    }
+
+    fn current(&self) -> Arc<A> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+impl<A: VerifyingAlgorithm> VerifyingAlgorithm for SwappableKey<A> {
+    fn algorithm_type(&self) -> AlgorithmType {
+        self.current().algorithm_type()
+    }
+
+    fn verify_bytes(&self, header: &str, claims: &str, signature: &[u8]) -> Result<bool, jwt::Error> {
+        self.current().verify_bytes(header, claims, signature)
+    }
+}
+
+impl<A: SigningAlgorithm> SigningAlgorithm for SwappableKey<A> {
+    fn algorithm_type(&self) -> AlgorithmType {
+        self.current().algorithm_type()
+    }
+
+    fn sign(&self, header: &str, claims: &str) -> Result<String, jwt::Error> {
+        self.current().sign(header, claims)
+    }
+}
+
+/// A [`Signer`] and [`Verifier`] sharing one HMAC-SHA256 key, so they can't be built with mismatched keys.
+pub fn hmac_pair(secret: &[u8], expiration: Duration, mode: VerifierMode) -> (Signer, Verifier<Hmac<Sha256>>) {
+    // HMAC takes keys of any length
+    let key = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    (Signer::new(key.clone(), expiration), Verifier::new(key, mode))
+}
+
 #[derive(Clone)]
 pub struct Signer {
     key: Arc<SigningAlgorithmWrapper>,
     expiration: Duration,
+    jti: bool,
+    cookie: Option<CookieConfig>,
+    clock: Clock,
+}
+
+/// How [`Signer::login_response`] sets the token as a cookie, the counterpart of [`Verifier::with_cookie`].
+#[derive(Clone)]
+pub struct CookieConfig {
+    pub name: String,
+    pub http_only: bool,
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub path: Option<String>,
+    /// Lifetime of the cookie, `None` keeps it for as long as the token is valid.
+    pub max_age: Option<Duration>,
+    /// Also returns the token in the JSON body, e.g. for clients that are not browsers.
+    pub in_body: bool,
+}
+
+impl CookieConfig {
+    /// An `HttpOnly`, `Secure`, `SameSite=Lax` cookie called `name`.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            http_only: true,
+            secure: true,
+            same_site: SameSite::Lax,
+            path: None,
+            max_age: None,
+            in_body: false,
+        }
+    }
+
+    fn cookie(&self, token: String, expiration: Duration) -> Cookie<'static> {
+        let max_age = self.max_age.unwrap_or(expiration);
+        let mut cookie = Cookie::build(self.name.clone(), token)
+            .http_only(self.http_only)
+            .secure(self.secure)
+            .same_site(self.same_site)
+            .max_age(time::Duration::seconds(max_age.as_secs() as i64))
+            .finish();
+        if let Some(path) = &self.path {
+            cookie.set_path(path.clone());
+        }
+        cookie
+    }
 }
 
 struct SigningAlgorithmWrapper(Box<dyn SigningAlgorithm + Send + Sync + 'static>);
@@ -290,17 +726,389 @@ impl SigningAlgorithm for SigningAlgorithmWrapper {
 
 impl Signer {
     pub fn new(key: impl SigningAlgorithm + Send + Sync + 'static, expiration: Duration) -> Self {
-        Self { key: Arc::new(SigningAlgorithmWrapper(Box::new(key))), expiration }
+        Self {
+            key: Arc::new(SigningAlgorithmWrapper(Box::new(key))),
+            expiration,
+            jti: false,
+            cookie: None,
+            clock: Arc::new(SystemTime::now),
+        }
     }
+
+    /// Stamps expiries, and the `exp` and `iat` claims on [`refresh`](Self::refresh), from `clock` instead of
+    /// the system clock.
+    pub fn with_clock(self, clock: Clock) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Makes [`login_response`](Self::login_response) set the token as a cookie instead of returning it in the body.
+    pub fn in_cookie(self, cookie: CookieConfig) -> Self {
+        Self { cookie: Some(cookie), ..self }
+    }
+
+    /// Stamps a fresh unique `jti` claim into every token, for revocation lists and replay detection.
+    /// Signing then fails with [`jwt::Error::Format`] if the claims are not an object or already have a `jti`.
+    pub fn with_jti(self, enabled: bool) -> Self {
+        Self { jti: enabled, ..self }
+    }
+
+    pub fn algorithm_type(&self) -> AlgorithmType {
+        self.key.algorithm_type()
+    }
+
     pub fn sign(&self, claims: impl ToBase64) -> Result<String, jwt::Error> {
+        self.sign_with_expiration(claims, self.expiration)
+    }
+
+    /// Signs `claims` into a successful login response, the token is returned as `{"token": "..."}` or set as
+    /// the cookie configured with [`in_cookie`](Self::in_cookie).
+    pub fn login_response(&self, claims: impl ToBase64) -> Result<HttpResponse, jwt::Error> {
+        let token = self.sign(claims)?;
+        let mut resp = HttpResponse::Ok();
+        match &self.cookie {
+            Some(cookie) if !cookie.in_body => {
+                return Ok(resp.cookie(cookie.cookie(token, self.expiration)).finish());
+            }
+            Some(cookie) => {
+                resp.cookie(cookie.cookie(token.clone(), self.expiration));
+            }
+            None => {}
+        }
+        Ok(resp.json(serde_json::json!({ "token": token })))
+    }
+
+    /// Re-mints a still valid `token`, as checked by `verifier`, with the claims kept and a fresh expiry. A
+    /// standard `exp` or `iat` claim is moved to the new lifetime and a `jti` replaced with a new one.
+    ///
+    /// This extends the access token itself, there is no separate refresh token to rotate, so a token
+    /// refreshed before it expires stays usable indefinitely.
+    pub fn refresh<A: VerifyingAlgorithm>(&self, verifier: &Verifier<A>, token: &str) -> Result<String, VerifierError> {
+        let mut claims = verifier.verify_token(token)?;
+        let serde_json::Value::Object(obj) = &mut claims else {
+            return Err(jwt::Error::Format.into());
+        };
+        let now = (self.clock)().duration_since(UNIX_EPOCH).unwrap_or_default();
+        if obj.contains_key("exp") {
+            obj.insert("exp".into(), (now + self.expiration).as_secs().into());
+        }
+        if obj.contains_key("iat") {
+            obj.insert("iat".into(), now.as_secs().into());
+        }
+        if obj.remove("jti").is_some() && !self.jti {
+            obj.insert("jti".into(), radix_32(gen_id()).to_string().into());
+        }
+        Ok(self.sign(claims)?)
+    }
+
+    /// Signs with a token lifetime chosen by the caller, e.g. a longer one for "remember me" logins.
+    pub fn sign_with_expiration(&self, claims: impl ToBase64, expiration: Duration) -> Result<String, jwt::Error> {
         let header = JwtHeader {
             inner: jwt::Header {
                 algorithm: self.key.algorithm_type(),
                 ..Default::default()
             },
-            expired_at: Time(SystemTime::now() + self.expiration),
+            expired_at: Time((self.clock)() + expiration),
+        };
+        let token = if self.jti {
+            let mut claims = serde_json::Value::from_base64(&*claims.to_base64()?)?;
+            let serde_json::Value::Object(obj) = &mut claims else {
+                return Err(jwt::Error::Format);
+            };
+            if obj.contains_key("jti") {
+                return Err(jwt::Error::Format);
+            }
+            obj.insert("jti".into(), radix_32(gen_id()).to_string().into());
+            Token::new(header, claims).sign_with_key(self.key.deref())?.as_str().to_owned()
+        } else {
+            Token::new(header, claims).sign_with_key(self.key.deref())?.as_str().to_owned()
+        };
+        Ok(token)
+    }
+}
+
+/// Signs and verifies tokens through the same code as [`Signer`] and the [`Verifier`] middleware,
+/// e.g. to check that tokens signed with an old and a new key both verify during a key rotation.
+#[cfg(feature = "test-util")]
+pub mod test_util {
+    use super::*;
+
+    pub fn sign_with(
+        algorithm: impl SigningAlgorithm + Send + Sync + 'static,
+        claims: impl ToBase64,
+        ttl: Duration,
+    ) -> String {
+        Signer::new(algorithm, ttl).sign(claims).expect("failed to sign token")
+    }
+
+    pub fn verify_with<A: VerifyingAlgorithm>(algorithm: A, token: &str) -> Result<serde_json::Value, VerifierError> {
+        Verifier::new(algorithm, VerifierMode::MustSuccess).verify_token(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{cookie::Cookie, test::TestRequest};
+    use serde_json::json;
+
+    fn verifier() -> Verifier<Hmac<Sha256>> {
+        Verifier::new(Hmac::new_from_slice(b"secret").unwrap(), VerifierMode::MustSuccess)
+    }
+
+    #[test]
+    fn test_check_issuer() {
+        let verifier = verifier().with_issuer("auth");
+        assert!(verifier.check_claims(&json!({"iss": "auth"})).is_ok());
+        assert!(matches!(verifier.check_claims(&json!({"iss": "other"})), Err(VerifierError::InvalidIssuer)));
+        assert!(matches!(verifier.check_claims(&json!({})), Err(VerifierError::InvalidIssuer)));
+    }
+
+    #[test]
+    fn test_status_codes() {
+        let (signer, verifier) = hmac_pair(b"secret", Duration::from_secs(60), VerifierMode::MustSuccess);
+        let status = |value: &str| verifier
+            .verify(&TestRequest::default().insert_header((header::AUTHORIZATION, value)).to_http_request())
+            .unwrap_err()
+            .status_code();
+        let other = Signer::new(Hmac::<Sha256>::new_from_slice(b"other").unwrap(), Duration::from_secs(60));
+
+        assert_eq!(status("Basic dXNlcg=="), StatusCode::BAD_REQUEST);
+        assert_eq!(status("Bearer not-a-jwt"), StatusCode::BAD_REQUEST);
+        assert_eq!(status(&format!("Bearer {}", other.sign(json!({})).unwrap())), StatusCode::UNAUTHORIZED);
+        let expired = signer.sign_with_expiration(json!({}), Duration::ZERO).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(status(&format!("Bearer {}", expired)), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_forbidden_on_mismatch() {
+        let (signer, verifier) = hmac_pair(b"secret", Duration::from_secs(60), VerifierMode::MustSuccess);
+        let verifier = verifier.with_audience("api").with_forbidden_on_mismatch(true);
+        let req = |claims| TestRequest::default()
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", signer.sign(claims).unwrap())))
+            .to_http_request();
+
+        let e = verifier.verify(&req(json!({"aud": "web"}))).unwrap_err();
+        assert_eq!(e.status_code(), StatusCode::FORBIDDEN);
+        assert!(e.error_response().headers().get(header::WWW_AUTHENTICATE).is_none());
+        assert!(verifier.verify(&req(json!({"aud": "api"}))).is_ok());
+        let e = verifier.verify(&TestRequest::default().to_http_request()).unwrap_err();
+        assert_eq!(e.status_code(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_check_audience() {
+        let verifier = verifier().with_audience("api");
+        assert!(verifier.check_claims(&json!({"aud": "api"})).is_ok());
+        assert!(verifier.check_claims(&json!({"aud": ["web", "api"]})).is_ok());
+        assert!(matches!(verifier.check_claims(&json!({"aud": "web"})), Err(VerifierError::InvalidAudience)));
+        assert!(matches!(verifier.check_claims(&json!({"aud": ["web"]})), Err(VerifierError::InvalidAudience)));
+        assert!(matches!(verifier.check_claims(&json!({})), Err(VerifierError::InvalidAudience)));
+    }
+
+    #[test]
+    fn test_token_header_before_cookie() {
+        let verifier = verifier().with_header_name("X-Auth").with_scheme("Token").with_cookie("session");
+        let req = TestRequest::default()
+            .insert_header(("X-Auth", "Token from-header"))
+            .cookie(Cookie::new("session", "from-cookie"))
+            .to_http_request();
+        assert_eq!(verifier.token(&req).unwrap(), "from-header");
+
+        let req = TestRequest::default().cookie(Cookie::new("session", "from-cookie")).to_http_request();
+        assert_eq!(verifier.token(&req).unwrap(), "from-cookie");
+
+        let req = TestRequest::default().insert_header(("X-Auth", "Bearer t")).to_http_request();
+        assert!(matches!(verifier.token(&req), Err(VerifierError::InvalidTokenType)));
+        assert!(matches!(verifier.token(&TestRequest::default().to_http_request()), Err(VerifierError::NotProvided)));
+    }
+
+    #[test]
+    fn test_diagnostics_accessors() {
+        assert_eq!(verifier().mode(), VerifierMode::MustSuccess);
+        assert_eq!(verifier().algorithm_type(), AlgorithmType::Hs256);
+        let signer = Signer::new(Hmac::<Sha256>::new_from_slice(b"secret").unwrap(), Duration::from_secs(60));
+        assert_eq!(signer.algorithm_type(), AlgorithmType::Hs256);
+    }
+
+    #[test]
+    fn test_swappable_key_rotation() {
+        let key = SwappableKey::new(Hmac::<Sha256>::new_from_slice(b"old").unwrap());
+        let signer = Signer::new(key.clone(), Duration::from_secs(60));
+        let verifier = Verifier::new(key.clone(), VerifierMode::MustSuccess);
+        let old_token = signer.sign(json!({"sub": "alice"})).unwrap();
+        assert!(verifier.verify_token(&old_token).is_ok());
+
+        key.replace(Hmac::<Sha256>::new_from_slice(b"new").unwrap());
+        assert!(verifier.verify_token(&old_token).is_err());
+        assert!(verifier.verify_token(&signer.sign(json!({"sub": "alice"})).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_claims_policy_exp_without_nbf() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let verifier = verifier().with_claims_policy(ClaimsPolicy { exp: true, ..Default::default() });
+        assert!(verifier.check_claims(&json!({"exp": now + 60, "nbf": now + 60})).is_ok());
+        assert!(matches!(verifier.check_claims(&json!({"exp": now - 1})), Err(VerifierError::Expired)));
+        assert!(matches!(verifier.check_claims(&json!({})), Err(VerifierError::MissingClaim("exp"))));
+    }
+
+    #[test]
+    fn test_claims_policy_nbf_and_jti() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let verifier = verifier().with_claims_policy(ClaimsPolicy { nbf: true, jti: true, ..Default::default() });
+        assert!(verifier.check_claims(&json!({"jti": "a", "nbf": now - 1})).is_ok());
+        assert!(verifier.check_claims(&json!({"jti": "a"})).is_ok());
+        assert!(matches!(verifier.check_claims(&json!({"jti": "a", "nbf": now + 60})), Err(VerifierError::NotYetValid)));
+        assert!(matches!(verifier.check_claims(&json!({"jti": 1})), Err(VerifierError::MissingClaim("jti"))));
+    }
+
+    #[test]
+    fn test_claims_policy_audience_ignoring_issuer() {
+        let verifier = verifier().with_issuer("auth").with_claims_policy(ClaimsPolicy {
+            audience: Some("api".to_string()),
+            ..Default::default()
+        });
+        assert!(verifier.check_claims(&json!({"aud": "api", "iss": "other"})).is_ok());
+        assert!(matches!(verifier.check_claims(&json!({"iss": "auth"})), Err(VerifierError::InvalidAudience)));
+    }
+
+    #[test]
+    fn test_claims_policy_without_expiry() {
+        let signer = Signer::new(Hmac::<Sha256>::new_from_slice(b"secret").unwrap(), Duration::ZERO);
+        let token = signer.sign(json!({})).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(matches!(verifier().verify_token(&token), Err(VerifierError::Expired)));
+        let verifier = verifier().with_claims_policy(ClaimsPolicy { expiry: false, ..Default::default() });
+        assert!(verifier.verify_token(&token).is_ok());
+    }
+
+    #[test]
+    fn test_hmac_pair() {
+        let (signer, verifier) = hmac_pair(b"secret", Duration::from_secs(60), VerifierMode::AllowFailed);
+        let token = signer.sign(json!({"sub": "alice"})).unwrap();
+        assert_eq!(verifier.verify_token(&token).unwrap()["sub"], "alice");
+        assert_eq!(verifier.mode(), VerifierMode::AllowFailed);
+    }
+
+    #[test]
+    fn test_check_claims_unconfigured() {
+        assert!(verifier().check_claims(&json!({"iss": "any", "aud": "any"})).is_ok());
+    }
+
+    #[test]
+    fn test_decode_unverified_ignores_signature() {
+        let signer = Signer::new(Hmac::<Sha256>::new_from_slice(b"other").unwrap(), Duration::from_secs(60));
+        let token = signer.sign(json!({"sub": "alice"})).unwrap();
+
+        let (_, claims) = decode_unverified(&token).unwrap();
+        assert_eq!(claims["sub"], "alice");
+        assert!(verifier().verify_token(&token).is_err());
+        assert!(decode_unverified("not a token").is_err());
+    }
+
+    #[test]
+    fn test_signer_with_jti() {
+        let signer = Signer::new(Hmac::<Sha256>::new_from_slice(b"secret").unwrap(), Duration::from_secs(60))
+            .with_jti(true);
+        let first = decode_unverified(&signer.sign(json!({"sub": "alice"})).unwrap()).unwrap().1;
+        let second = decode_unverified(&signer.sign(json!({"sub": "alice"})).unwrap()).unwrap().1;
+        assert!(first["jti"].is_string());
+        assert_ne!(first["jti"], second["jti"]);
+
+        assert!(matches!(signer.sign(json!({"jti": "mine"})), Err(jwt::Error::Format)));
+        assert!(matches!(signer.sign(json!("not an object")), Err(jwt::Error::Format)));
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn test_util_rotation_overlap() {
+        use test_util::{sign_with, verify_with};
+
+        let old = || Hmac::<Sha256>::new_from_slice(b"old").unwrap();
+        let new = || Hmac::<Sha256>::new_from_slice(b"new").unwrap();
+        let token = sign_with(old(), json!({"sub": "alice"}), Duration::from_secs(60));
+        assert_eq!(verify_with(old(), &token).unwrap()["sub"], "alice");
+        assert!(verify_with(new(), &token).is_err());
+    }
+
+    #[test]
+    fn test_refresh_keeps_claims() {
+        let (signer, verifier) = hmac_pair(b"secret", Duration::from_secs(60), VerifierMode::MustSuccess);
+        let signer = signer.with_jti(true);
+        let token = signer.sign(json!({"sub": "alice", "iat": 1})).unwrap();
+        let old = verifier.verify_token(&token).unwrap();
+
+        let refreshed = verifier.verify_token(&signer.refresh(&verifier, &token).unwrap()).unwrap();
+        assert_eq!(refreshed["sub"], "alice");
+        assert!(refreshed["iat"].as_u64().unwrap() > 1);
+        assert_ne!(refreshed["jti"], old["jti"]);
+
+        let (expired, _) = hmac_pair(b"secret", Duration::ZERO, VerifierMode::MustSuccess);
+        let token = expired.sign(json!({"sub": "alice"})).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(matches!(signer.refresh(&verifier, &token), Err(VerifierError::Expired)));
+    }
+
+    #[test]
+    fn test_login_response_in_cookie() {
+        let (signer, verifier) = hmac_pair(b"secret", Duration::from_secs(60), VerifierMode::MustSuccess);
+        let signer = signer.in_cookie(CookieConfig { path: Some("/".to_string()), ..CookieConfig::new("session") });
+
+        let resp = signer.login_response(json!({"sub": "alice"})).unwrap();
+        let cookie = resp.cookies().next().unwrap();
+        assert_eq!(cookie.name(), "session");
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.max_age(), Some(time::Duration::seconds(60)));
+        assert_eq!(verifier.verify_token(cookie.value()).unwrap()["sub"], "alice");
+        assert_eq!(resp.body().size(), actix_web::body::BodySize::Sized(0));
+    }
+
+    #[test]
+    fn test_clock_expiry_boundary() {
+        let now = Arc::new(std::sync::Mutex::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let clock: Clock = {
+            let now = now.clone();
+            Arc::new(move || *now.lock().unwrap())
+        };
+        let signer = Signer::new(Hmac::<Sha256>::new_from_slice(b"secret").unwrap(), Duration::from_secs(60))
+            .with_clock(clock.clone());
+        let verifier = verifier()
+            .with_clock(clock)
+            .with_claims_policy(ClaimsPolicy { exp: true, nbf: true, ..Default::default() });
+        let claims = json!({"exp": 1_700_000_030, "nbf": 1_700_000_010});
+        let token = signer.sign(&claims).unwrap();
+
+        assert!(matches!(verifier.verify_token(&token), Err(VerifierError::NotYetValid)));
+        *now.lock().unwrap() += Duration::from_secs(10);
+        assert!(verifier.verify_token(&token).is_ok());
+        *now.lock().unwrap() += Duration::from_secs(19);
+        assert!(verifier.verify_token(&token).is_ok());
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert!(matches!(verifier.verify_token(&token), Err(VerifierError::Expired)));
+    }
+
+    #[test]
+    fn test_error_reasons() {
+        let (signer, _) = hmac_pair(b"other", Duration::from_secs(60), VerifierMode::MustSuccess);
+        let token = signer.sign(json!({"sub": "alice"})).unwrap();
+        assert_eq!(verifier().verify_token(&token).unwrap_err().reason(), "invalid_signature");
+        assert_eq!(verifier().verify_token("garbage").unwrap_err().reason(), "malformed");
+        assert_eq!(VerifierError::Forbidden(Box::new(VerifierError::InvalidAudience)).reason(), "invalid_audience");
+    }
+
+    #[test]
+    fn test_subject_extractor() {
+        let extract = |claims: serde_json::Value| {
+            let req = TestRequest::default().to_http_request();
+            req.extensions_mut().insert(ClaimsValue(Ok(Arc::new(claims))));
+            Subject::extract(&req).into_inner()
         };
-        let token = Token::new(header, claims).sign_with_key(self.key.deref())?;
-        Ok(token.as_str().to_owned())
+        assert_eq!(&*extract(json!({"sub": "alice"})).unwrap(), "alice");
+        let err = extract(json!({"name": "alice"})).err().unwrap();
+        assert_eq!(err.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
     }
 }
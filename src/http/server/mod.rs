@@ -2,11 +2,19 @@
 pub mod jwt;
 #[cfg(feature = "http-server-tracer")]
 pub mod tracer;
+#[cfg(feature = "http-health")]
+pub mod health;
+#[cfg(feature = "http-cors")]
+pub mod cors;
+#[cfg(feature = "http-timeout")]
+pub mod timeout;
+#[cfg(feature = "http-server")]
+pub mod stack;
 
-#[cfg(any(feature = "http-jwt"))]
+#[cfg(feature = "http-jwt")]
 pub mod extract {
     #[cfg(feature = "http-jwt")]
-    pub use super::jwt::Jwt;
+    pub use super::jwt::{Jwt, RawClaims, Subject};
 }
 #[cfg(any(feature = "http-jwt", feature = "http-server-tracer"))]
 pub mod middleware {
@@ -0,0 +1,112 @@
+use super::{
+    cors::{cors, CorsConfig},
+    jwt::Verifier,
+    timeout::Timeout,
+    tracer::Tracer,
+};
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    App, Error,
+};
+use jwt::VerifyingAlgorithm;
+use std::time::Duration;
+
+/// An [`App`] with the crate's middlewares wrapped in working order, add routes with `.service(..)` or
+/// `.configure(..)` as usual, they run under every middleware whatever the order they are added in.
+///
+/// From the outside in:
+/// 1. CORS, so preflights are answered before the verifier rejects them for lacking a token, and the
+///    401s and 504s from further in still carry CORS headers browsers need to read them. Errors from below
+///    pass through as errors, CORS adds its headers when actix renders them into responses. It exposes
+///    the tracer's trace id header, whatever `trace_id_header` in `cors_config` says.
+/// 2. The tracer, so every response below, rejections and timeouts included, is logged with its trace id.
+/// 3. The timeout, under the tracer so the tracer sees and logs the 504.
+/// 4. The JWT verifier, whose claims the handlers extract.
+pub fn standard_app<A>(
+    tracer: Tracer,
+    verifier: Verifier<A>,
+    timeout: Duration,
+    cors_config: &CorsConfig,
+) -> App<impl ServiceFactory<ServiceRequest, Config=(), Response=ServiceResponse<impl MessageBody>, Error=Error, InitError=()>>
+where
+    A: VerifyingAlgorithm + Clone + 'static,
+{
+    let cors_config = CorsConfig { trace_id_header: tracer.trace_id_header_name(), ..cors_config.clone() };
+    // the last `wrap` is the outermost
+    App::new()
+        .wrap(verifier)
+        .wrap(Timeout(timeout))
+        .wrap(tracer)
+        .wrap(cors(&cors_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::server::jwt::{hmac_pair, VerifierMode};
+    use actix_web::{http::{header, Method, StatusCode}, test, web, HttpResponse};
+    use hmac::{Hmac, Mac as _};
+    use sha2::Sha256;
+
+    #[tokio::test]
+    async fn test_rejections_carry_cors_headers() {
+        let verifier = Verifier::new(Hmac::<Sha256>::new_from_slice(b"secret").unwrap(), VerifierMode::MustSuccess);
+        let cors_config = CorsConfig { allowed_origins: vec!["https://app.example.com".to_string()], ..Default::default() };
+        let app = test::init_service(
+            standard_app(Tracer::trace_only(), verifier, Duration::from_secs(5), &cors_config)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        ).await;
+
+        let preflight = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .insert_header((header::ORIGIN, "https://app.example.com"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "GET"))
+            .to_request();
+        let resp = test::call_service(&app, preflight).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .insert_header((header::ORIGIN, "https://app.example.com"))
+            .to_request();
+        let Err(e) = test::try_call_service(&app, req).await else { panic!("request passed the verifier") };
+        let resp = e.error_response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_routes_reachable() {
+        let (signer, verifier) = hmac_pair(b"secret", Duration::from_secs(60), VerifierMode::MustSuccess);
+        let app = test::init_service(
+            standard_app(Tracer::trace_only(), verifier, Duration::from_secs(5), &CorsConfig::default())
+                .route("/users/{id}", web::get().to(|id: web::Path<String>| async move { id.into_inner() })),
+        ).await;
+
+        let req = test::TestRequest::get()
+            .uri("/users/42")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", signer.sign(serde_json::json!({})).unwrap())))
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "42");
+    }
+
+    #[tokio::test]
+    async fn test_exposes_configured_trace_id_header() {
+        let (signer, verifier) = hmac_pair(b"secret", Duration::from_secs(60), VerifierMode::MustSuccess);
+        let cors_config = CorsConfig { allowed_origins: vec!["https://app.example.com".to_string()], ..Default::default() };
+        let tracer = Tracer::trace_only().trace_id_header("X-Request-Trace");
+        let app = test::init_service(
+            standard_app(tracer, verifier, Duration::from_secs(5), &cors_config)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        ).await;
+
+        let req = test::TestRequest::get()
+            .insert_header((header::ORIGIN, "https://app.example.com"))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", signer.sign(serde_json::json!({})).unwrap())))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let exposed = resp.headers().get(header::ACCESS_CONTROL_EXPOSE_HEADERS).unwrap().to_str().unwrap();
+        assert!(exposed.contains("x-request-trace"), "{}", exposed);
+        assert!(!exposed.contains("x-trace-id"), "{}", exposed);
+    }
+}
@@ -0,0 +1,88 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error, rt, Error,
+};
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    time::Duration,
+};
+
+/// Answers 504 when the wrapped service takes longer than the duration, dropping its future. The 504 is
+/// returned as an [`Error`], holding on to the request to build a response would break routing below.
+///
+/// Wrap it before the tracer, i.e. `.wrap(Timeout(..)).wrap(Tracer::..)`, so the tracer sees and logs the 504.
+#[derive(Copy, Clone)]
+pub struct Timeout(pub Duration);
+
+impl<S, B> Transform<S, ServiceRequest> for Timeout
+where
+    S: Service<ServiceRequest, Response=ServiceResponse<B>, Error=Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = TimeoutMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TimeoutMiddleware {
+            timeout: self.0,
+            service,
+        }))
+    }
+}
+
+pub struct TimeoutMiddleware<S> {
+    timeout: Duration,
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for TimeoutMiddleware<S>
+where
+    S: Service<ServiceRequest, Response=ServiceResponse<B>, Error=Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let future = self.service.call(req);
+        let timeout = self.timeout;
+        Box::pin(async move {
+            rt::time::timeout(timeout, future).await
+                .unwrap_or_else(|_| Err(error::ErrorGatewayTimeout("request timed out")))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let app = test::init_service(
+            App::new()
+                .wrap(Timeout(Duration::from_millis(20)))
+                .route("/fast/{id}", web::get().to(|id: web::Path<String>| async move { id.into_inner() }))
+                .route("/slow", web::get().to(|| async {
+                    rt::time::sleep(Duration::from_secs(5)).await;
+                    HttpResponse::Ok().finish()
+                })),
+        ).await;
+
+        let req = test::TestRequest::get().uri("/fast/42").to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "42");
+
+        let req = test::TestRequest::get().uri("/slow").to_request();
+        let Err(e) = test::try_call_service(&app, req).await else { panic!("slow request did not time out") };
+        assert_eq!(e.as_response_error().status_code(), StatusCode::GATEWAY_TIMEOUT);
+    }
+}
@@ -1,16 +1,20 @@
-use crate::{
-    http::trace_util::{def_format_headers, def_tracer, TraceConfig, TRACE_ID_HEADER},
-    util::radix32::from_radix_32,
+use crate::http::trace_util::{
+    decode_body, def_format_headers, def_record_header_sizes, def_tracer, span_at, status_class, AccessLog, TraceConfig,
+    REQ_HEADER_SIZE_FIELDS, RESP_HEADER_SIZE_FIELDS,
 };
+pub use crate::http::trace_util::{RequestIdFormat, TraceIdEncoding};
+use actix_http::BoxedPayloadStream;
 use actix_web::{
     body::{self, BodySize, BoxBody, MessageBody},
-    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
     error,
     http::header::{self, HeaderMap},
     Error,
     HttpMessage,
+    HttpRequest,
 };
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use futures_util::{stream, StreamExt as _};
 use pin_project::pin_project;
 use std::{
     future::{ready, Future, Ready},
@@ -18,10 +22,36 @@ use std::{
     rc::Rc,
     task::{ready, Context, Poll},
 };
-use tracing::{error, field::Empty, trace, trace_span, Instrument, Span};
+use tracing::{error, field::Empty, trace, Instrument, Span};
 
 def_tracer!(pub Tracer);
 
+// options only the server side has a use for, kept out of `def_tracer!` so the client tracer lacks them
+impl Tracer {
+    /// The header the trace id is read from and answered in, e.g. to expose it through CORS.
+    pub fn trace_id_header_name(&self) -> &'static str {
+        self.0.trace_id_header
+    }
+
+    pub fn log_client_ip(self) -> Self {
+        Self(TraceConfig { log_client_ip: true, ..self.0 })
+    }
+    /// Seeds server spans' trace id from the upstream request id in `name`, which is also recorded as `request_id`.
+    pub fn request_id_header(self, name: &'static str, format: RequestIdFormat) -> Self {
+        Self(TraceConfig { request_id_header: Some((name, format)), ..self.0 })
+    }
+    /// Requests to these paths, e.g. `/healthz` or `/metrics/*`, are forwarded untouched without a span or logs.
+    pub fn skip_paths(self, skip_paths: &'static [&'static str]) -> Self {
+        Self(TraceConfig { skip_paths, ..self.0 })
+    }
+    /// Records these params of the matched route, e.g. `user_id` of `/users/{user_id}`, as `path_params`
+    /// like `user_id=42`. Params are only known once the route matched, so they are recorded when the
+    /// response is ready and events logged by the handler itself don't carry them.
+    pub fn path_params(self, path_params: &'static [&'static str]) -> Self {
+        Self(TraceConfig { path_params, ..self.0 })
+    }
+}
+
 impl<S, B> Transform<S, ServiceRequest> for Tracer
 where
     S: Service<ServiceRequest, Response=ServiceResponse<B>, Error=Error> + 'static,
@@ -58,7 +88,10 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let span = span_from_request(&req);
+        if self.trace_config.skips(req.path()) {
+            return TracerFuture::Skipped(self.service.call(req));
+        }
+        let span = span_from_request(&req, &self.trace_config);
         if self.trace_config.log_req_body_size > 0 {
             TracerFuture::WithLogBody(Box::pin(with_log_body(
                 req,
@@ -84,6 +117,7 @@ where
 {
     WithoutLogBody(#[pin] WithoutLogBody<Fut>),
     WithLogBody(#[pin] Pin<Box<dyn Future<Output=Result<ServiceResponse<BoxBody>, Error>>>>),
+    Skipped(#[pin] Fut),
 }
 
 impl<Fut, B> Future for TracerFuture<Fut>
@@ -102,6 +136,9 @@ where
             TFP::WithLogBody(future) => {
                 Poll::Ready(ready!(future.poll(cx)))
             }
+            TFP::Skipped(future) => {
+                Poll::Ready(ready!(future.poll(cx)).map(ServiceResponse::map_into_boxed_body))
+            }
         }
     }
 }
@@ -113,6 +150,8 @@ pub struct WithoutLogBody<Fut> {
     #[pin]
     future: Fut,
     req_headers: Option<String>,
+    // boxed to keep the future small when access logs are off
+    access_log: Option<Box<AccessLog>>,
 }
 
 impl<Fut, B> WithoutLogBody<Fut>
@@ -125,10 +164,12 @@ where
     {
         let req_headers = trace_config.log_req_headers
             .then_some(format_headers(req.headers()));
+        let access_log = access_log(&req, &trace_config);
         Self {
             span,
             trace_config,
             req_headers,
+            access_log,
             future: service.call(req),
         }
     }
@@ -146,24 +187,30 @@ where
         let _guard = this.span.enter();
 
         let result = ready!(this.future.poll(cx))
-            .map(|resp| resp.map_into_boxed_body());
+            .map(|resp| count_resp_bytes(resp, this.span, 0));
+        if let Some(access_log) = this.access_log {
+            log_access(access_log, &result);
+        }
         match result {
             Ok(ref resp) => {
-                this.span.record("status", resp.status().as_u16());
+                record_status(this.span, resp);
+                if this.trace_config.log_header_sizes {
+                    record_header_sizes(this.span, resp.headers(), RESP_HEADER_SIZE_FIELDS);
+                }
+                record_path_params(this.span, resp.request(), this.trace_config);
                 let should_log_headers = resp.response().status().is_server_error() ||
                     !this.trace_config.only_on_error ||
                     this.trace_config.always_log_headers;
 
-                if this.req_headers.is_some() && should_log_headers {
-                    trace!(req_headers=this.req_headers.as_ref().unwrap())
+                if let Some(req_headers) = this.req_headers.as_ref().filter(|_| should_log_headers) {
+                    trace!(req_headers=req_headers)
                 }
                 if this.trace_config.log_resp_headers && should_log_headers {
                     trace!(resp_headers=format_headers(resp.headers()));
                 }
-                resp.response().error().map(|e| {
+                if let Some(e) = resp.response().error() {
                     log_error(e);
-                    e
-                });
+                }
             }
             Err(ref e) => {
                 log_error(e);
@@ -173,23 +220,111 @@ where
     }
 }
 
-fn span_from_request(req: &ServiceRequest) -> Span {
-    let id = req.headers().get(TRACE_ID_HEADER)
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| from_radix_32(s));
+fn span_from_request(req: &ServiceRequest, trace_config: &TraceConfig) -> Span {
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let request_id = trace_config.request_id_header
+        .and_then(|(name, format)| header(name).map(|v| (v, format)));
+    let id = request_id
+        .and_then(|(v, format)| checked_id(trace_config.request_id_header?.0, v, format.decode(v)))
+        .or_else(|| header(trace_config.trace_id_header)
+            .and_then(|v| checked_id(trace_config.trace_id_header, v, trace_config.trace_id_encoding.decode(v))));
     let id = id.as_ref()
         .map(|id| id as &dyn tracing::Value)
         .unwrap_or(&Empty);
-    trace_span!(
+    let span = span_at!(
+        trace_config.span_level,
         "handle http request",
         trace_id=id,
         uri=%req.uri(),
         method=%req.method(),
+        user_agent=req.headers().get(header::USER_AGENT).and_then(|v| v.to_str().ok()),
+        client_ip=Empty,
+        request_id=request_id.map(|(v, _)| v),
+        path_params=Empty,
         status=Empty,
-    )
+        status_class=Empty,
+        content_type=Empty,
+        resp_bytes=Empty,
+        req_header_count=Empty,
+        req_header_bytes=Empty,
+        resp_header_count=Empty,
+        resp_header_bytes=Empty,
+    );
+    if trace_config.log_header_sizes {
+        record_header_sizes(&span, req.headers(), REQ_HEADER_SIZE_FIELDS);
+    }
+    if trace_config.log_client_ip {
+        if let Some(ip) = client_ip(req) {
+            span.record("client_ip", ip.as_str());
+        }
+    }
+    span
+}
+
+/// Passes a decoded id through, noting ids that were sent but could not be parsed so broken propagation shows up.
+fn checked_id(header: &str, value: &str, id: Option<u128>) -> Option<u128> {
+    const MAX_LOGGED_LEN: usize = 64;
+    if id.is_none() {
+        // header values that made it through `to_str` are visible ascii, so any byte offset is a char boundary
+        let value = &value[..value.len().min(MAX_LOGGED_LEN)];
+        trace!(header, value, "unparseable incoming trace id, generating a new one");
+    }
+    id
+}
+
+fn access_log(req: &ServiceRequest, trace_config: &TraceConfig) -> Option<Box<AccessLog>> {
+    trace_config.access_log
+        .then(|| Box::new(AccessLog::start(req.method().as_str(), req.path(), content_len(req))))
+}
+
+fn log_access<B: MessageBody>(access_log: &AccessLog, result: &Result<ServiceResponse<B>, Error>) {
+    match result {
+        Ok(resp) => {
+            let resp_bytes = match resp.response().body().size() {
+                BodySize::Sized(size) => Some(size),
+                BodySize::None => Some(0),
+                BodySize::Stream => None,
+            };
+            access_log.log(Some(resp.status().as_u16()), resp_bytes)
+        }
+        Err(e) => access_log.log(Some(e.as_response_error().status_code().as_u16()), None),
+    }
+}
+
+fn record_status<B>(span: &Span, resp: &ServiceResponse<B>) {
+    span.record("status", resp.status().as_u16());
+    span.record("status_class", status_class(resp.status().as_u16()));
+    if let Some(content_type) = resp.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        span.record("content_type", content_type);
+    }
+}
+
+fn record_path_params(span: &Span, req: &HttpRequest, trace_config: &TraceConfig) {
+    if trace_config.path_params.is_empty() {
+        return;
+    }
+    let params = req.match_info()
+        .iter()
+        .filter(|(name, _)| trace_config.path_params.contains(name))
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>();
+    if !params.is_empty() {
+        span.record("path_params", params.join(","));
+    }
+}
+
+fn client_ip(req: &ServiceRequest) -> Option<String> {
+    if let Some(addr) = req.peer_addr() {
+        return Some(addr.ip().to_string());
+    }
+    req.headers().get(header::X_FORWARDED_FOR)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(|ip| ip.trim().to_owned())
 }
 
 def_format_headers!(HeaderMap);
+def_record_header_sizes!(HeaderMap);
 
 async fn with_log_body<S, B>(
     mut req: ServiceRequest,
@@ -203,22 +338,33 @@ where
 {
     let req_headers = trace_config.log_req_headers
         .then_some(format_headers(req.headers()));
+    let access_log = access_log(&req, &trace_config);
+    let req_encoding = content_encoding(req.headers()).map(str::to_owned);
     let req_body = get_req_body(&mut req, trace_config.log_req_body_size).await?;
 
-    let resp = service.call(req).await
-        .map_err(|e| {
-            log_error(&e);
-            e
-        })?;
-    span.record("status", resp.status().as_u16());
-
+    let result = service.call(req).await;
+    if let Some(access_log) = &access_log {
+        log_access(access_log, &result);
+    }
+    let resp = result.inspect_err(log_error)?;
     let should_log = resp.response().status().is_server_error() || !trace_config.only_on_error;
+    let capture_size = if should_log { trace_config.log_resp_body_size } else { 0 };
+    let resp = count_resp_bytes(resp, &span, capture_size);
+    record_status(&span, &resp);
+    if trace_config.log_header_sizes {
+        record_header_sizes(&span, resp.headers(), RESP_HEADER_SIZE_FIELDS);
+    }
+    record_path_params(&span, resp.request(), &trace_config);
+
     let should_log_headers = should_log || trace_config.always_log_headers;
-    if req_headers.is_some() && should_log_headers {
-        trace!(req_headers=req_headers.unwrap())
+    if let Some(req_headers) = req_headers.filter(|_| should_log_headers) {
+        trace!(req_headers=req_headers)
     }
-    if req_body.is_some() && should_log {
-        trace!(req_body=%String::from_utf8_lossy(&req_body.unwrap()));
+    if let Some(req_body) = req_body.filter(|_| should_log) {
+        match decode_body(req_encoding.as_deref(), &req_body, trace_config.log_req_body_size) {
+            Ok(body) => trace!(req_body=%String::from_utf8_lossy(&body)),
+            Err(e) => trace!("request body not logged: {}", e),
+        }
     }
     if trace_config.log_resp_headers && should_log_headers {
         trace!(resp_headers=format_headers(resp.headers()));
@@ -227,7 +373,7 @@ where
         return log_resp_body(resp, trace_config.log_resp_body_size).await;
     }
 
-    Ok(resp.map_into_boxed_body())
+    Ok(resp)
 }
 
 async fn get_req_body(req: &mut ServiceRequest, max_size: u64) -> Result<Option<Bytes>, Error> {
@@ -237,27 +383,35 @@ async fn get_req_body(req: &mut ServiceRequest, max_size: u64) -> Result<Option<
     if !is_text(req.headers()) {
         return Ok(None);
     }
-    match content_len(req) {
-        None => {
-            return Ok(None)
+    if let Some(len) = content_len(req) {
+        if len > max_size {
+            trace!("request body size {} bytes exceeds maximum length of {} bytes", len, max_size);
+            return Ok(None);
         }
-        Some(len) => {
-            if len > max_size {
-                trace!("request body size {} bytes exceeds maximum length of {} bytes", len, max_size);
-                return Ok(None);
-            }
+    }
+
+    // the body may be streamed without a content length, so cap on the bytes actually read
+    let mut payload = req.take_payload();
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| {
+            let e = e.into();
+            log_error(&e);
+            e
+        })?;
+        buf.extend_from_slice(&chunk);
+        if buf.len() as u64 > max_size {
+            trace!("request body exceeds maximum length of {} bytes", max_size);
+            let read = stream::once(ready(Ok(buf.freeze())));
+            let payload: BoxedPayloadStream = Box::pin(read.chain(payload));
+            req.set_payload(Payload::from(payload));
+            return Ok(None);
         }
     }
-    let payload = req.take_payload();
-    let stream = body::BodyStream::new(payload);
-    let buf = body::to_bytes(stream).await.map_err(|e| {
-        let e = e.into();
-        log_error(&e);
-        e
-    })?;
+    let buf = buf.freeze();
     let (_, mut payload) = actix_http::h1::Payload::create(true);
     payload.unread_data(buf.clone());
-    req.set_payload(actix_web::dev::Payload::from(payload));
+    req.set_payload(Payload::from(payload));
     Ok(Some(buf))
 }
 
@@ -278,11 +432,11 @@ where
         }
         BodySize::Stream => { return Ok(resp.map_into_boxed_body()); }
     };
+    let encoding = content_encoding(resp.headers()).map(str::to_owned);
     let (req, resp) = resp.into_parts();
-    resp.error().map(|e| {
+    if let Some(e) = resp.error() {
         log_error(e);
-        e
-    });
+    }
     let (resp, body) = resp.into_parts();
     let body_bytes = body::to_bytes(body).await
         .map_err(|e| {
@@ -290,12 +444,103 @@ where
             log_error(&e);
             e
         })?;
-    trace!(resp_body=%String::from_utf8_lossy(&body_bytes));
+    match decode_body(encoding.as_deref(), &body_bytes, max_size) {
+        Ok(body) => trace!(resp_body=%String::from_utf8_lossy(&body)),
+        Err(e) => trace!("response body not logged: {}", e),
+    }
     let resp = resp.set_body(body_bytes.boxed());
     let resp = ServiceResponse::new(req, resp);
     Ok(resp)
 }
 
+/// Records `resp_bytes` on the span, counting streamed bodies chunk by chunk as they are sent. With a
+/// `capture_size` streamed text bodies are also logged, up to that many bytes, once they have been sent.
+fn count_resp_bytes<B: MessageBody + 'static>(resp: ServiceResponse<B>, span: &Span, capture_size: u64) -> ServiceResponse<BoxBody> {
+    match resp.response().body().size() {
+        BodySize::Stream => {
+            let capture = (capture_size > 0 && is_text(resp.headers())).then(|| BodyCapture {
+                max_size: capture_size,
+                encoding: content_encoding(resp.headers()).map(str::to_owned),
+                buf: BytesMut::new(),
+            });
+            resp.map_body(|_, body| CountedBody { body, span: span.clone(), bytes: 0, capture }.boxed())
+        }
+        size => {
+            if let BodySize::Sized(size) = size {
+                span.record("resp_bytes", size);
+            }
+            resp.map_into_boxed_body()
+        }
+    }
+}
+
+/// Passes a streamed body through untouched, recording its total size on the span once it has been fully sent.
+#[pin_project]
+struct CountedBody<B> {
+    #[pin]
+    body: B,
+    span: Span,
+    bytes: u64,
+    capture: Option<BodyCapture>,
+}
+
+/// The first `max_size` bytes of a streamed body, kept for logging while the rest streams through.
+struct BodyCapture {
+    max_size: u64,
+    encoding: Option<String>,
+    buf: BytesMut,
+}
+
+impl BodyCapture {
+    fn push(&mut self, chunk: &[u8]) {
+        let remaining = self.max_size.saturating_sub(self.buf.len() as u64) as usize;
+        self.buf.extend_from_slice(&chunk[..chunk.len().min(remaining)]);
+    }
+
+    fn log(&self, total: u64) {
+        if total <= self.max_size {
+            match decode_body(self.encoding.as_deref(), &self.buf, self.max_size) {
+                Ok(body) => trace!(resp_body=%String::from_utf8_lossy(&body)),
+                Err(e) => trace!("response body not logged: {}", e),
+            }
+        } else if self.encoding.as_deref().is_some_and(|encoding| !encoding.trim().eq_ignore_ascii_case("identity")) {
+            // a prefix of a compressed stream can't be decoded on its own
+            trace!("response body not logged: compressed body exceeds maximum length of {} bytes", self.max_size);
+        } else {
+            trace!(resp_body=%String::from_utf8_lossy(&self.buf), resp_body_truncated=true);
+        }
+    }
+}
+
+impl<B: MessageBody> MessageBody for CountedBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.project();
+        let chunk = ready!(this.body.poll_next(cx));
+        match &chunk {
+            Some(Ok(bytes)) => {
+                *this.bytes += bytes.len() as u64;
+                if let Some(capture) = this.capture {
+                    capture.push(bytes);
+                }
+            }
+            Some(Err(_)) => {}
+            None => {
+                this.span.record("resp_bytes", *this.bytes);
+                if let Some(capture) = this.capture.take() {
+                    this.span.in_scope(|| capture.log(*this.bytes));
+                }
+            }
+        }
+        Poll::Ready(chunk)
+    }
+}
+
 fn log_error(e: &Error) {
     if e.as_response_error().status_code().is_server_error() {
         error!("SERVER_INTERNAL_ERROR: {:?}", e)
@@ -310,6 +555,11 @@ fn content_len(req: &ServiceRequest) -> Option<u64> {
         .and_then(|s| s.parse::<u64>().ok())
 }
 
+fn content_encoding(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+}
+
 fn is_text(headers: &HeaderMap) -> bool {
     headers.get(header::CONTENT_TYPE)
         .and_then(|v| v.to_str().ok())
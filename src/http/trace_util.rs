@@ -1,6 +1,61 @@
-pub const TRACE_ID_HEADER: &str = "X-Trace-Id";
+pub(crate) use super::TRACE_ID_HEADER;
 
-#[derive(Copy, Clone, Default)]
+/// How trace ids are written in the trace id header.
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+pub enum TraceIdEncoding {
+    /// The crate's own lowercase radix 32, as logged by the tracing layer.
+    #[default]
+    Base32,
+    /// Lowercase hex, as emitted by most other tracers.
+    Base16,
+}
+
+impl TraceIdEncoding {
+    #[cfg(feature = "http-client")]
+    pub(crate) fn encode(self, id: u128) -> String {
+        match self {
+            TraceIdEncoding::Base32 => crate::util::radix32::radix_32(id).to_string(),
+            TraceIdEncoding::Base16 => format!("{:x}", id),
+        }
+    }
+
+    #[cfg(feature = "http-server-tracer")]
+    pub(crate) fn decode(self, s: &str) -> Option<u128> {
+        match self {
+            TraceIdEncoding::Base32 => crate::util::radix32::from_radix_32(s),
+            TraceIdEncoding::Base16 => (matches!(s.len(), 1..=32) && s.bytes().all(|c| c.is_ascii_hexdigit()))
+                .then(|| u128::from_str_radix(s, 16).ok())
+                .flatten(),
+        }
+    }
+}
+
+/// How an upstream request id header maps onto a trace id.
+#[cfg(feature = "http-server-tracer")]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum RequestIdFormat {
+    Base16,
+    Base32,
+    /// Any string, such as a UUID, hashed into a trace id.
+    Opaque,
+}
+
+#[cfg(feature = "http-server-tracer")]
+impl RequestIdFormat {
+    pub(crate) fn decode(self, s: &str) -> Option<u128> {
+        match self {
+            RequestIdFormat::Base16 => TraceIdEncoding::Base16.decode(s),
+            RequestIdFormat::Base32 => TraceIdEncoding::Base32.decode(s),
+            // 128 bit FNV-1a
+            RequestIdFormat::Opaque => (!s.is_empty()).then(|| s.bytes().fold(
+                0x6c62272e07bb014262b821756295c58d_u128,
+                |hash, b| (hash ^ b as u128).wrapping_mul(0x0000000001000000000000000000013b),
+            )),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
 pub struct TraceConfig {
     pub log_req_headers: bool,
     pub log_resp_headers: bool,
@@ -8,6 +63,109 @@ pub struct TraceConfig {
     pub log_resp_body_size: u64,
     pub only_on_error: bool,
     pub always_log_headers: bool,
+    /// Records the client ip on server spans, off by default as ips are personal data.
+    #[cfg(feature = "http-server-tracer")]
+    pub log_client_ip: bool,
+    /// Header carrying the trace id, read by the server tracer and set by the client tracer.
+    pub trace_id_header: &'static str,
+    pub trace_id_encoding: TraceIdEncoding,
+    /// Header, such as `X-Request-Id`, the server tracer takes the trace id from before the trace id header.
+    #[cfg(feature = "http-server-tracer")]
+    pub request_id_header: Option<(&'static str, RequestIdFormat)>,
+    /// Request paths the server tracer passes through without a span, `/internal/*` matches by prefix.
+    #[cfg(feature = "http-server-tracer")]
+    pub skip_paths: &'static [&'static str],
+    /// Emits one `info` event per request with its method, path, status, latency and sizes.
+    pub access_log: bool,
+    /// Level of the request spans, the header and body events keep their own levels.
+    pub span_level: tracing::Level,
+    /// Path params the server tracer records as `path_params`, others are left out as they may be sensitive ids.
+    #[cfg(feature = "http-server-tracer")]
+    pub path_params: &'static [&'static str],
+    /// Records the header count and total name plus value bytes of requests and responses as span fields.
+    pub log_header_sizes: bool,
+}
+
+impl TraceConfig {
+    #[cfg(feature = "http-server-tracer")]
+    pub(crate) fn skips(&self, path: &str) -> bool {
+        self.skip_paths.iter().any(|skip| match skip.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == *skip,
+        })
+    }
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self {
+            log_req_headers: false,
+            log_resp_headers: false,
+            log_req_body_size: 0,
+            log_resp_body_size: 0,
+            only_on_error: false,
+            always_log_headers: false,
+            #[cfg(feature = "http-server-tracer")]
+            log_client_ip: false,
+            trace_id_header: TRACE_ID_HEADER,
+            trace_id_encoding: TraceIdEncoding::default(),
+            #[cfg(feature = "http-server-tracer")]
+            request_id_header: None,
+            #[cfg(feature = "http-server-tracer")]
+            skip_paths: &[],
+            access_log: false,
+            span_level: tracing::Level::TRACE,
+            #[cfg(feature = "http-server-tracer")]
+            path_params: &[],
+            log_header_sizes: false,
+        }
+    }
+}
+
+/// The `status_class` span field, e.g. `4xx`, to break traffic down by without a value per status.
+pub(crate) fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+pub(crate) const REQ_HEADER_SIZE_FIELDS: (&str, &str) = ("req_header_count", "req_header_bytes");
+pub(crate) const RESP_HEADER_SIZE_FIELDS: (&str, &str) = ("resp_header_count", "resp_header_bytes");
+
+/// The single summary event of a request, logged once it has a status.
+pub(crate) struct AccessLog {
+    method: String,
+    path: String,
+    req_bytes: Option<u64>,
+    started_at: std::time::Instant,
+}
+
+impl AccessLog {
+    pub(crate) fn start(method: &str, path: &str, req_bytes: Option<u64>) -> Self {
+        Self {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            req_bytes,
+            started_at: std::time::Instant::now(),
+        }
+    }
+
+    /// `status` is `None` when no response was received at all.
+    pub(crate) fn log(&self, status: Option<u16>, resp_bytes: Option<u64>) {
+        tracing::info!(
+            method=%self.method,
+            path=%self.path,
+            status,
+            latency_ms=self.started_at.elapsed().as_millis() as u64,
+            req_bytes=self.req_bytes,
+            resp_bytes,
+            "access log",
+        );
+    }
 }
 
 macro_rules! def_tracer {
@@ -57,23 +215,196 @@ macro_rules! def_tracer {
             $vis fn only_on_error(self, always_log_headers: bool) -> Self {
                 Self(TraceConfig { only_on_error: true, always_log_headers, ..self.0 })
             }
+            /// `name` must be a valid header name, it defaults to `X-Trace-Id`.
+            $vis fn trace_id_header(self, name: &'static str) -> Self {
+                Self(TraceConfig { trace_id_header: name, ..self.0 })
+            }
+            $vis fn trace_id_encoding(self, trace_id_encoding: TraceIdEncoding) -> Self {
+                Self(TraceConfig { trace_id_encoding, ..self.0 })
+            }
+            /// Adds an `info` access log event per request, independent of the header and body traces.
+            $vis fn access_log(self) -> Self {
+                Self(TraceConfig { access_log: true, ..self.0 })
+            }
+            /// Creates request spans at `level` instead of `trace`, e.g. to keep them with an `info` filter.
+            $vis fn span_level(self, span_level: tracing::Level) -> Self {
+                Self(TraceConfig { span_level, ..self.0 })
+            }
+            /// Records `req_header_count`/`req_header_bytes` and `resp_header_count`/`resp_header_bytes` on the
+            /// span, cheap numbers that are safe to keep on unlike full header logs.
+            $vis fn log_header_sizes(self) -> Self {
+                Self(TraceConfig { log_header_sizes: true, ..self.0 })
+            }
         }
     };
 }
 pub(crate) use def_tracer;
 
+// span macros take the level as a constant, so expand one callsite per level
+macro_rules! span_at {
+    ($level:expr, $($args:tt)*) => {
+        match $level {
+            tracing::Level::ERROR => tracing::span!(tracing::Level::ERROR, $($args)*),
+            tracing::Level::WARN => tracing::span!(tracing::Level::WARN, $($args)*),
+            tracing::Level::INFO => tracing::span!(tracing::Level::INFO, $($args)*),
+            tracing::Level::DEBUG => tracing::span!(tracing::Level::DEBUG, $($args)*),
+            tracing::Level::TRACE => tracing::span!(tracing::Level::TRACE, $($args)*),
+        }
+    };
+}
+pub(crate) use span_at;
+
 macro_rules! def_format_headers {
     ($ident:ident) => {
+        /// One `name: value` line per value, the values of a repeated header such as `Set-Cookie` on adjacent
+        /// lines. Values that are not UTF-8 are written as `<non-utf8 0x..>` with their bytes in hex.
         fn format_headers(headers: &$ident) -> String {
+            use std::fmt::Write as _;
+
             let mut buf = String::new();
-            for (k, v) in headers {
-                buf.push_str(k.as_str());
-                buf.push_str(": ");
-                buf.push_str(&String::from_utf8_lossy(v.as_bytes()).to_owned());
-                buf.push_str("\n");
+            for k in headers.keys() {
+                for v in headers.get_all(k) {
+                    buf.push_str(k.as_str());
+                    buf.push_str(": ");
+                    match std::str::from_utf8(v.as_bytes()) {
+                        Ok(v) => buf.push_str(v),
+                        Err(_) => {
+                            buf.push_str("<non-utf8 0x");
+                            for b in v.as_bytes() {
+                                let _ = write!(buf, "{:02x}", b);
+                            }
+                            buf.push('>');
+                        }
+                    }
+                    buf.push('\n');
+                }
             }
             buf
         }
     };
 }
-pub(crate) use def_format_headers;
\ No newline at end of file
+pub(crate) use def_format_headers;
+
+macro_rules! def_record_header_sizes {
+    ($ident:ident) => {
+        /// Records the number of headers and the sum of their name and value lengths in the `(count, bytes)` fields.
+        fn record_header_sizes(span: &tracing::Span, headers: &$ident, (count_field, bytes_field): (&str, &str)) {
+            let bytes = headers.iter()
+                .map(|(k, v)| (k.as_str().len() + v.as_bytes().len()) as u64)
+                .sum::<u64>();
+            span.record(count_field, headers.len() as u64);
+            span.record(bytes_field, bytes);
+        }
+    };
+}
+pub(crate) use def_record_header_sizes;
+#[cfg(any(feature = "http-client", feature = "http-server-tracer"))]
+#[derive(Debug)]
+pub enum DecodeError {
+    UnsupportedEncoding(String),
+    Corrupted,
+    TooLarge(u64),
+}
+
+#[cfg(any(feature = "http-client", feature = "http-server-tracer"))]
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnsupportedEncoding(encoding) => write!(f, "unsupported content encoding '{}'", encoding),
+            DecodeError::Corrupted => f.write_str("failed to decompress body"),
+            DecodeError::TooLarge(max_size) => write!(f, "decompressed body exceeds maximum length of {} bytes", max_size),
+        }
+    }
+}
+
+/// Decompresses a `gzip`/`deflate`/`br` encoded body for logging, reading at most `max_size` decompressed bytes.
+#[cfg(any(feature = "http-client", feature = "http-server-tracer"))]
+pub fn decode_body<'a>(encoding: Option<&str>, body: &'a [u8], max_size: u64) -> Result<std::borrow::Cow<'a, [u8]>, DecodeError> {
+    use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+    use std::{borrow::Cow, io::Read};
+
+    fn read_limited(reader: impl Read, max_size: u64) -> Result<Vec<u8>, DecodeError> {
+        let mut buf = Vec::new();
+        reader.take(max_size + 1)
+            .read_to_end(&mut buf)
+            .map_err(|_| DecodeError::Corrupted)?;
+        if buf.len() as u64 > max_size {
+            return Err(DecodeError::TooLarge(max_size));
+        }
+        Ok(buf)
+    }
+
+    let encoding = match encoding.map(str::trim) {
+        None | Some("") | Some("identity") => return Ok(Cow::Borrowed(body)),
+        Some(encoding) => encoding.to_ascii_lowercase(),
+    };
+    match encoding.as_str() {
+        "gzip" | "x-gzip" => read_limited(GzDecoder::new(body), max_size).map(Cow::Owned),
+        // `deflate` should be zlib wrapped, but some servers send a raw deflate stream.
+        "deflate" => match read_limited(ZlibDecoder::new(body), max_size) {
+            Err(DecodeError::Corrupted) => read_limited(DeflateDecoder::new(body), max_size),
+            result => result,
+        }.map(Cow::Owned),
+        "br" => read_limited(brotli_decompressor::Decompressor::new(body, 4096), max_size).map(Cow::Owned),
+        _ => Err(DecodeError::UnsupportedEncoding(encoding)),
+    }
+}
+
+#[cfg(all(test, feature = "http-client", feature = "http-server-tracer"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_id_round_trip() {
+        for encoding in [TraceIdEncoding::Base32, TraceIdEncoding::Base16] {
+            for id in [1, 31, 32, 0x1234_5678_9abc_def0_1234_5678_9abc_def0, u128::MAX] {
+                assert_eq!(encoding.decode(&encoding.encode(id)), Some(id));
+            }
+        }
+    }
+
+    #[test]
+    fn test_trace_id_decode_rejects_invalid() {
+        assert_eq!(TraceIdEncoding::Base32.decode("w"), None);
+        assert_eq!(TraceIdEncoding::Base32.decode("80000000000000000000000000"), None);
+        assert_eq!(TraceIdEncoding::Base16.decode("+1"), None);
+        assert_eq!(TraceIdEncoding::Base16.decode(""), None);
+    }
+
+    #[test]
+    fn test_decode_brotli_body() {
+        // `{"hello":"brotli"}` as compressed by the reference encoder
+        const BODY: &[u8] = &[
+            139, 8, 128, 123, 34, 104, 101, 108, 108, 111, 34, 58, 34, 98, 114, 111, 116, 108, 105, 34, 125, 3,
+        ];
+
+        assert_eq!(&*decode_body(Some("br"), BODY, 1024).unwrap(), br#"{"hello":"brotli"}"#);
+        assert!(matches!(decode_body(Some("br"), BODY, 4), Err(DecodeError::TooLarge(4))));
+        assert!(matches!(decode_body(Some("br"), &BODY[..10], 1024), Err(DecodeError::Corrupted)));
+    }
+
+    #[test]
+    fn test_status_class() {
+        assert_eq!(status_class(101), "1xx");
+        assert_eq!(status_class(204), "2xx");
+        assert_eq!(status_class(499), "4xx");
+        assert_eq!(status_class(504), "5xx");
+    }
+
+    #[test]
+    fn test_format_headers_groups_values() {
+        use http::{HeaderMap, HeaderValue};
+        def_format_headers!(HeaderMap);
+
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", HeaderValue::from_static("a=1"));
+        headers.append("content-type", HeaderValue::from_static("text/plain"));
+        headers.append("set-cookie", HeaderValue::from_static("b=2"));
+        headers.append("x-binary", HeaderValue::from_bytes(b"\xffok").unwrap());
+
+        let formatted = format_headers(&headers);
+        assert!(formatted.contains("set-cookie: a=1\nset-cookie: b=2\n"));
+        assert!(formatted.contains("content-type: text/plain\n"));
+        assert!(formatted.contains("x-binary: <non-utf8 0xff6f6b>\n"));
+    }
+}
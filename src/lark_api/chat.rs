@@ -0,0 +1,133 @@
+use super::{client::{read_json, Client, CommonResp, BASE_URL}, error::Result, page::{paginate, Page}};
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+
+#[derive(Deserialize, Debug)]
+pub struct Member {
+    /// The member's open_id.
+    pub member_id: String,
+    pub name: String,
+    pub tenant_key: Option<String>,
+}
+
+impl Client {
+    /// Creates a group chat with the given members, identified by open_id, and returns its chat_id.
+    pub async fn create_chat(&self, name: &str, member_ids: &[&str]) -> Result<String> {
+        static URL: LazyLock<url::Url> = LazyLock::new(|| {
+            BASE_URL.join("im/v1/chats").unwrap()
+        });
+        #[derive(Serialize)]
+        struct Request<'a> {
+            name: &'a str,
+            user_id_list: &'a [&'a str],
+        }
+        #[derive(Deserialize)]
+        struct Response {
+            #[serde(flatten)]
+            common_resp: CommonResp,
+            data: Option<Data>,
+        }
+        #[derive(Deserialize)]
+        struct Data {
+            chat_id: String,
+        }
+
+        self.with_non_idempotent_retry_policy(|| async {
+            let token = self.token().await?;
+            self.throttle().await;
+            let resp = self.client.post(URL.clone())
+                .header("Authorization", token)
+                .query(&[("user_id_type", "open_id")])
+                .json(&Request {
+                    name,
+                    user_id_list: member_ids,
+                })
+                .send()
+                .await?;
+            let resp: Response = read_json(resp).await?;
+            match resp.data {
+                Some(data) if resp.common_resp.code == 0 => Ok(data.chat_id),
+                _ => Err(resp.common_resp.into()),
+            }
+        }).await
+    }
+
+    /// Adds members, identified by open_id, to a chat and returns the ids that could not be added.
+    pub async fn add_chat_members(&self, chat_id: &str, member_ids: &[&str]) -> Result<Vec<String>> {
+        #[derive(Serialize)]
+        struct Request<'a> {
+            id_list: &'a [&'a str],
+        }
+        #[derive(Deserialize)]
+        struct Response {
+            #[serde(flatten)]
+            common_resp: CommonResp,
+            data: Option<Data>,
+        }
+        #[derive(Deserialize)]
+        struct Data {
+            #[serde(default)]
+            invalid_id_list: Vec<String>,
+            #[serde(default)]
+            not_existed_id_list: Vec<String>,
+        }
+
+        let url = BASE_URL.join(&format!("im/v1/chats/{}/members", chat_id))?;
+        self.with_retry_policy(|| async {
+            let token = self.token().await?;
+            self.throttle().await;
+            let resp = self.client.post(url.clone())
+                .header("Authorization", token)
+                .query(&[("member_id_type", "open_id")])
+                .json(&Request {
+                    id_list: member_ids,
+                })
+                .send()
+                .await?;
+            let resp: Response = read_json(resp).await?;
+            if resp.common_resp.code != 0 {
+                return Err(resp.common_resp.into());
+            }
+            Ok(resp.data
+                .map(|data| [data.invalid_id_list, data.not_existed_id_list].concat())
+                .unwrap_or_default())
+        }).await
+    }
+
+    /// Lists the members of a chat, fetching further pages as the stream is consumed.
+    pub fn list_chat_members<'a>(&'a self, chat_id: &'a str) -> impl Stream<Item=Result<Member>> + 'a {
+        paginate(move |page_token: Option<String>| async move {
+            self.chat_members_page(chat_id, page_token.as_deref()).await
+        })
+    }
+
+    async fn chat_members_page(&self, chat_id: &str, page_token: Option<&str>) -> Result<Page<Member>> {
+        #[derive(Deserialize)]
+        struct Response {
+            #[serde(flatten)]
+            common_resp: CommonResp,
+            data: Option<Page<Member>>,
+        }
+
+        let url = BASE_URL.join(&format!("im/v1/chats/{}/members", chat_id))?;
+        self.with_retry_policy(|| async {
+            let token = self.token().await?;
+            self.throttle().await;
+            let mut query = vec![("member_id_type", "open_id"), ("page_size", "100")];
+            if let Some(page_token) = page_token {
+                query.push(("page_token", page_token));
+            }
+            let resp = self.client.get(url.clone())
+                .header("Authorization", token)
+                .query(&query)
+                .send()
+                .await?;
+            let resp: Response = read_json(resp).await?;
+            match resp.data {
+                Some(data) if resp.common_resp.code == 0 => Ok(data),
+                _ => Err(resp.common_resp.into()),
+            }
+        }).await
+    }
+}
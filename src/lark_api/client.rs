@@ -1,20 +1,120 @@
-use super::error::Result;
+use super::error::{Error, Result};
 use http::HeaderValue;
 use reqwest_middleware::ClientWithMiddleware;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::future::Future;
 use std::sync::LazyLock;
 use std::time;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-pub const BASE_URL: LazyLock<url::Url> = LazyLock::new(|| {
+pub static BASE_URL: LazyLock<url::Url> = LazyLock::new(|| {
     url::Url::parse("https://open.larksuite.com/open-apis/").unwrap()
 });
 
+/// Lark allows 50 requests per second for most IM APIs.
+pub const DEFAULT_RATE_LIMIT: u32 = 50;
+
 pub struct Client {
     app_id: String,
     app_secret: String,
     pub(super) client: ClientWithMiddleware,
-    token: tokio::sync::RwLock<Token>,
+    tenant_token: tokio::sync::RwLock<Token>,
+    app_token: tokio::sync::RwLock<Token>,
+    limiter: Option<RateLimiter>,
+    retry: Option<RetryPolicy>,
+    #[cfg(feature = "lark-api-etcd")]
+    token_cache: Option<super::token_cache::EtcdTokenCache>,
+}
+
+/// Retries failed calls with jittered exponential backoff, only transport errors and Lark's
+/// rate limit codes are retried. Calls that are not safe to repeat, such as sending an ephemeral card, are
+/// only retried when the request never reached Lark or was rate limited.
+#[derive(Copy, Clone)]
+pub struct RetryPolicy {
+    /// Attempts including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each further one.
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(200),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // a random point in the upper half of the backoff, so clients failing together don't retry together
+    fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.base_delay.saturating_mul(1 << retry.min(16));
+        let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        backoff / 2 + backoff.mul_f64(nanos as f64 / 2e9)
+    }
+}
+
+// a timed out or cut off call may have been carried out, so only `idempotent` ones are retried then
+fn is_transient(e: &Error, idempotent: bool) -> bool {
+    match e {
+        Error::HttpError(reqwest_middleware::Error::Reqwest(e)) => {
+            e.is_connect() || idempotent && (e.is_timeout() || e.is_request())
+        }
+        Error::HttpError(reqwest_middleware::Error::Middleware(_)) => false,
+        Error::DeserializeError(e) => idempotent && (e.is_timeout() || e.is_body()),
+        // gateways answer 5xx with a non-JSON page
+        Error::StatusError { status, .. } => {
+            *status == reqwest::StatusCode::TOO_MANY_REQUESTS || idempotent && status.is_server_error()
+        }
+        Error::RequestError(resp) => resp.is_retryable(),
+        _ => false,
+    }
+}
+
+/// Reads the JSON body of `resp`. A body that fails to decode is only a [`Error::DeserializeError`] on a
+/// 2xx status, on others it is a [`Error::StatusError`] as the body is likely no Lark response at all.
+pub(super) async fn read_json<T: DeserializeOwned>(resp: reqwest::Response) -> Result<T> {
+    let status = resp.status();
+    resp.json().await.map_err(|e| match e.is_decode() && !status.is_success() {
+        true => Error::StatusError { status, source: e },
+        false => e.into(),
+    })
+}
+
+/// Token bucket allowing bursts of up to one second worth of requests, tracked as the time the bucket is full again.
+struct RateLimiter {
+    interval: Duration,
+    burst: Duration,
+    full_at: tokio::sync::Mutex<time::Instant>,
+}
+
+impl RateLimiter {
+    fn new(max_per_second: u32) -> Self {
+        let interval = Duration::from_secs(1) / max_per_second;
+        Self {
+            interval,
+            burst: interval * (max_per_second - 1),
+            full_at: tokio::sync::Mutex::new(time::Instant::now()),
+        }
+    }
+
+    async fn acquire(&self) {
+        let mut full_at = self.full_at.lock().await;
+        let now = time::Instant::now();
+        let at = (*full_at).max(now);
+        if let Some(wait) = at.checked_sub(self.burst).and_then(|t| t.checked_duration_since(now)) {
+            tokio::time::sleep(wait).await;
+        }
+        *full_at = at + self.interval;
+    }
+}
+
+/// Access token kind, most APIs take the tenant token while some app-level APIs need the app token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Tenant,
+    App,
 }
 
 struct Token {
@@ -22,6 +122,15 @@ struct Token {
     expired_at: time::Instant,
 }
 
+impl Token {
+    fn expired() -> tokio::sync::RwLock<Self> {
+        tokio::sync::RwLock::new(Token {
+            token: HeaderValue::from_str("").unwrap(),
+            expired_at: time::Instant::now(),
+        })
+    }
+}
+
 #[derive(thiserror::Error, Deserialize, Debug)]
 #[error("code: {code}, msg: {msg}")]
 pub struct CommonResp {
@@ -29,23 +138,174 @@ pub struct CommonResp {
     pub msg: String,
 }
 
+impl CommonResp {
+    /// The access token is missing, invalid or expired, a fresh token may succeed.
+    pub fn is_token_error(&self) -> bool {
+        const TOKEN_CODES: &[i64] = &[99991661, 99991663, 99991664, 99991668, 99991677];
+        TOKEN_CODES.contains(&self.code)
+    }
+
+    /// A frequency limit was hit, across the open platform or for IM.
+    pub fn is_rate_limited(&self) -> bool {
+        const RATE_LIMIT_CODES: &[i64] = &[99991400, 230020];
+        RATE_LIMIT_CODES.contains(&self.code)
+    }
+
+    /// The same call may succeed later as is, other codes are permanent business errors, apart from
+    /// [token errors](Self::is_token_error) which need a new token first.
+    pub fn is_retryable(&self) -> bool {
+        self.is_rate_limited()
+    }
+}
+
 impl Client {
+    /// `client` is usually the service's `http::client::shared_client`, so Lark calls are traced and time out like its others.
     pub fn new(app_id: String, app_secret: String, client: ClientWithMiddleware) -> Self {
         Self {
             app_id,
             app_secret,
             client,
-            token: tokio::sync::RwLock::new(Token {
-                token: HeaderValue::from_str("").unwrap(),
-                expired_at: time::Instant::now(),
-            }),
+            tenant_token: Token::expired(),
+            app_token: Token::expired(),
+            limiter: Some(RateLimiter::new(DEFAULT_RATE_LIMIT)),
+            retry: None,
+            #[cfg(feature = "lark-api-etcd")]
+            token_cache: None,
         }
     }
 
-    pub(super) async fn get_token(&self) -> Result<HeaderValue> {
-        const URL: LazyLock<url::Url> = LazyLock::new(|| {
+    /// Builds the HTTP client with the crate's [`Tracer`](crate::http::client::Tracer) installed,
+    /// so Lark calls are logged with the current trace id.
+    #[cfg(feature = "http-client")]
+    pub fn new_traced(app_id: String, app_secret: String, tracer: crate::http::client::Tracer) -> Self {
+        Self::new(app_id, app_secret, crate::http::client::default_with_trace(tracer))
+    }
+
+    /// Limits outgoing requests to `max_per_second`, calls over the limit wait for their turn. `None` disables limiting.
+    pub fn with_rate_limit(mut self, max_per_second: Option<u32>) -> Self {
+        self.limiter = max_per_second
+            .filter(|n| *n > 0)
+            .map(RateLimiter::new);
+        self
+    }
+
+    /// Retries API calls failing with transient errors according to `policy`. `None`, the default, disables retries.
+    pub fn with_retry(mut self, policy: Option<RetryPolicy>) -> Self {
+        self.retry = policy.filter(|policy| policy.max_attempts > 1);
+        self
+    }
+
+    /// Shares access tokens with every client of the same app using `etcd`, under `<prefix>/<app_id>/<kind>`.
+    ///
+    /// Each client still caches the token in memory. Once that expires it reads the shared one, and only when that
+    /// is missing or expired does a single replica, holding an etcd lock, fetch a new token for all. While etcd is
+    /// unreachable tokens are fetched directly, as without the shared cache.
+    #[cfg(feature = "lark-api-etcd")]
+    pub fn with_etcd_token_cache(mut self, etcd: etcd_client::Client, prefix: &str) -> Self {
+        self.token_cache = Some(super::token_cache::EtcdTokenCache::new(etcd, prefix));
+        self
+    }
+
+    pub(super) async fn with_retry_policy<T, F, Fut>(&self, f: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output=Result<T>>,
+    {
+        self.retry(true, f).await
+    }
+
+    /// Like [`with_retry_policy`](Self::with_retry_policy) for calls a repeat could carry out twice, e.g.
+    /// creating a chat, retried only when the request never got through or was rate limited.
+    pub(super) async fn with_non_idempotent_retry_policy<T, F, Fut>(&self, f: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output=Result<T>>,
+    {
+        self.retry(false, f).await
+    }
+
+    async fn retry<T, F, Fut>(&self, idempotent: bool, f: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output=Result<T>>,
+    {
+        let policy = match self.retry {
+            Some(policy) => policy,
+            None => return f().await,
+        };
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(e) if attempt < policy.max_attempts && is_transient(&e, idempotent) => {
+                    tokio::time::sleep(policy.delay(attempt - 1)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub(super) async fn throttle(&self) {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+    }
+
+    /// The underlying http client, to call endpoints this crate doesn't model yet with [`token`](Self::token).
+    /// Prefer the modeled methods, which also apply the rate limit and retries.
+    pub fn http(&self) -> &ClientWithMiddleware {
+        &self.client
+    }
+
+    /// The tenant `Authorization` header value most endpoints take, fetched or refreshed as needed.
+    pub async fn token(&self) -> Result<HeaderValue> {
+        self.access_token(TokenKind::Tenant).await
+    }
+
+    /// Returns the `Authorization` header value for the given token kind, fetching a new token when the cached one expired.
+    pub async fn access_token(&self, kind: TokenKind) -> Result<HeaderValue> {
+        let token = match kind {
+            TokenKind::Tenant => &self.tenant_token,
+            TokenKind::App => &self.app_token,
+        };
+
+        let t = token.read().await;
+        if t.expired_at.elapsed().is_zero() {
+            return Ok(t.token.clone());
+        }
+        drop(t);
+        let mut t = token.write().await;
+        if t.expired_at.elapsed().is_zero() {
+            return Ok(t.token.clone());
+        }
+
+        #[cfg(feature = "lark-api-etcd")]
+        let (access_token, valid_for) = match &self.token_cache {
+            Some(cache) => {
+                let kind_name = match kind {
+                    TokenKind::Tenant => "tenant",
+                    TokenKind::App => "app",
+                };
+                cache.get_or_refresh(&self.app_id, kind_name, || self.fetch_token(kind)).await?
+            }
+            None => self.fetch_token(kind).await?,
+        };
+        #[cfg(not(feature = "lark-api-etcd"))]
+        let (access_token, valid_for) = self.fetch_token(kind).await?;
+
+        t.token = HeaderValue::from_str(&format!("Bearer {}", access_token))?;
+        t.expired_at = time::Instant::now() + valid_for;
+        Ok(t.token.clone())
+    }
+
+    /// Fetches a new token and how long it may be used, a minute short of its actual expiry.
+    async fn fetch_token(&self, kind: TokenKind) -> Result<(String, Duration)> {
+        static TENANT_URL: LazyLock<url::Url> = LazyLock::new(|| {
             BASE_URL.join("auth/v3/tenant_access_token/internal").unwrap()
         });
+        static APP_URL: LazyLock<url::Url> = LazyLock::new(|| {
+            BASE_URL.join("auth/v3/app_access_token/internal").unwrap()
+        });
         #[derive(Serialize)]
         struct Request<'a> {
             app_id: &'a str,
@@ -55,34 +315,34 @@ impl Client {
         struct Response {
             #[serde(flatten)]
             common_resp: CommonResp,
-            tenant_access_token: String,
+            tenant_access_token: Option<String>,
+            app_access_token: Option<String>,
             expire: u64,
         }
 
-        let t = self.token.read().await;
-        if t.expired_at.elapsed().is_zero() {
-            return Ok(t.token.clone());
-        }
-        drop(t);
-        let mut t = self.token.write().await;
-        if t.expired_at.elapsed().is_zero() {
-            return Ok(t.token.clone());
-        }
+        let url = match kind {
+            TokenKind::Tenant => &*TENANT_URL,
+            TokenKind::App => &*APP_URL,
+        };
 
-        let resp = self.client.post(URL.clone())
+        self.throttle().await;
+        let resp = self.client.post(url.clone())
             .json(&Request {
                 app_id: &self.app_id,
                 app_secret: &self.app_secret,
             })
             .send()
             .await?;
-        let resp: Response = resp.json().await?;
-        if resp.common_resp.code != 0 {
-            return Err(resp.common_resp.into());
+        let resp: Response = read_json(resp).await?;
+        let access_token = match kind {
+            TokenKind::Tenant => resp.tenant_access_token,
+            TokenKind::App => resp.app_access_token,
+        };
+        match access_token {
+            Some(access_token) if resp.common_resp.code == 0 => {
+                Ok((access_token, Duration::from_secs(resp.expire.saturating_sub(60))))
+            }
+            _ => Err(resp.common_resp.into()),
         }
-
-        t.token = HeaderValue::from_str(&format!("Bearer {}", resp.tenant_access_token))?;
-        t.expired_at = time::Instant::now() + Duration::from_secs(resp.expire.checked_sub(60).unwrap_or(0));
-        Ok(t.token.clone())
     }
 }
@@ -0,0 +1,101 @@
+use super::{client::{read_json, Client, CommonResp, BASE_URL}, error::Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::LazyLock};
+
+#[derive(Deserialize, Debug)]
+pub struct UserInfo {
+    pub open_id: String,
+    #[serde(default)]
+    pub union_id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub en_name: Option<String>,
+    /// Only present with the contact scope for emails.
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Only present with the contact scope for phone numbers.
+    #[serde(default)]
+    pub mobile: Option<String>,
+}
+
+impl Client {
+    /// Looks up the open_ids of users by email and mobile, up to 50 of each per call. The map goes from the
+    /// email or mobile as given to the open_id, identifiers matching no user are left out.
+    pub async fn get_user_ids(&self, emails: &[&str], mobiles: &[&str]) -> Result<HashMap<String, String>> {
+        static URL: LazyLock<url::Url> = LazyLock::new(|| {
+            BASE_URL.join("contact/v3/users/batch_get_id").unwrap()
+        });
+        #[derive(Serialize)]
+        struct Request<'a> {
+            emails: &'a [&'a str],
+            mobiles: &'a [&'a str],
+        }
+        #[derive(Deserialize)]
+        struct Response {
+            #[serde(flatten)]
+            common_resp: CommonResp,
+            data: Option<Data>,
+        }
+        #[derive(Deserialize)]
+        struct Data {
+            #[serde(default)]
+            user_list: Vec<UserId>,
+        }
+        #[derive(Deserialize)]
+        struct UserId {
+            user_id: Option<String>,
+            email: Option<String>,
+            mobile: Option<String>,
+        }
+
+        self.with_retry_policy(|| async {
+            let token = self.token().await?;
+            self.throttle().await;
+            let resp = self.client.post(URL.clone())
+                .header("Authorization", token)
+                .query(&[("user_id_type", "open_id")])
+                .json(&Request { emails, mobiles })
+                .send()
+                .await?;
+            let resp: Response = read_json(resp).await?;
+            if resp.common_resp.code != 0 {
+                return Err(resp.common_resp.into());
+            }
+            Ok(resp.data
+                .map(|data| data.user_list.into_iter()
+                    .filter_map(|user| Some((user.email.or(user.mobile)?, user.user_id?)))
+                    .collect())
+                .unwrap_or_default())
+        }).await
+    }
+
+    /// Gets a user's profile by open_id.
+    pub async fn get_user(&self, open_id: &str) -> Result<UserInfo> {
+        #[derive(Deserialize)]
+        struct Response {
+            #[serde(flatten)]
+            common_resp: CommonResp,
+            data: Option<Data>,
+        }
+        #[derive(Deserialize)]
+        struct Data {
+            user: UserInfo,
+        }
+
+        let url = BASE_URL.join(&format!("contact/v3/users/{}", open_id))?;
+        self.with_retry_policy(|| async {
+            let token = self.token().await?;
+            self.throttle().await;
+            let resp = self.client.get(url.clone())
+                .header("Authorization", token)
+                .query(&[("user_id_type", "open_id")])
+                .send()
+                .await?;
+            let resp: Response = read_json(resp).await?;
+            match resp.data {
+                Some(data) if resp.common_resp.code == 0 => Ok(data.user),
+                _ => Err(resp.common_resp.into()),
+            }
+        }).await
+    }
+}
@@ -11,6 +11,12 @@ pub enum Error {
     HttpError(#[from] reqwest_middleware::Error),
     #[error("deserialize error: {0}")]
     DeserializeError(#[from] reqwest::Error),
+    /// A non-2xx response whose body is not the JSON expected, such as a gateway's error page.
+    #[error("unexpected response status {status}: {source}")]
+    StatusError {
+        status: reqwest::StatusCode,
+        source: reqwest::Error,
+    },
     #[error("request error: {0}")]
     RequestError(#[from] CommonResp),
     #[error("invalid access token: {0}")]
@@ -1,15 +1,65 @@
+use crate::tracing::spawn_with_trace;
 use actix_web::web::Json;
 use actix_web::{post, web, Either, HttpRequest, HttpResponse, Responder, Scope};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum Event {
     MessageReceive(MessageReceiveEvent),
+    CardAction(CardActionEvent),
+}
+
+/// A click on an interactive card component.
+#[derive(Debug)]
+pub struct CardActionEvent {
+    pub open_id: String,
+    pub message_id: String,
+    pub chat_id: String,
+    /// The `value` configured on the triggering component.
+    pub value: serde_json::Value,
+}
+
+impl CardActionEvent {
+    fn from_raw(raw: CardActionEventRaw) -> Self {
+        Self {
+            open_id: raw.operator.open_id,
+            message_id: raw.context.open_message_id,
+            chat_id: raw.context.open_chat_id,
+            value: raw.action.value,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CardActionEventRaw {
+    operator: OperatorRaw,
+    action: ActionRaw,
+    context: ContextRaw,
+}
+#[derive(Deserialize)]
+struct OperatorRaw {
+    open_id: String,
+}
+#[derive(Deserialize)]
+struct ActionRaw {
+    #[serde(default)]
+    value: serde_json::Value,
+}
+#[derive(Deserialize)]
+struct ContextRaw {
+    open_message_id: String,
+    open_chat_id: String,
 }
 
 #[derive(Debug)]
 pub struct MessageReceiveEvent {
+    pub message_id: String,
+    /// Id of the first message of the thread, `None` unless the message is a reply.
+    pub root_id: Option<String>,
+    /// Id of the message directly replied to, `None` unless the message is a reply.
+    pub parent_id: Option<String>,
     pub chat_id: String,
     pub chat_type: ChatType,
     pub message: Message,
@@ -22,6 +72,12 @@ pub enum ChatType {
 #[derive(Debug)]
 pub enum Message {
     Text(String),
+    /// image_key of the received image.
+    Image(String),
+    /// file_key of the received file.
+    File(String),
+    /// Rich text content as sent by Lark.
+    Post(serde_json::Value),
 }
 
 impl MessageReceiveEvent {
@@ -37,9 +93,23 @@ impl MessageReceiveEvent {
                     .ok()?.text;
                 Message::Text(text)
             }
+            "image" => {
+                let image_key = serde_json::from_str::<ImageMessage>(&raw.message.content)
+                    .ok()?.image_key;
+                Message::Image(image_key)
+            }
+            "file" => {
+                let file_key = serde_json::from_str::<FileMessage>(&raw.message.content)
+                    .ok()?.file_key;
+                Message::File(file_key)
+            }
+            "post" => Message::Post(serde_json::from_str(&raw.message.content).ok()?),
             _ => return None,
         };
         Some(Self {
+            message_id: raw.message.message_id,
+            root_id: raw.message.root_id.filter(|id| !id.is_empty()),
+            parent_id: raw.message.parent_id.filter(|id| !id.is_empty()),
             chat_id: raw.message.chat_id,
             chat_type,
             message,
@@ -53,6 +123,11 @@ struct MessageReceiveEventRaw {
 }
 #[derive(Deserialize)]
 struct MessageRaw {
+    message_id: String,
+    #[serde(default)]
+    root_id: Option<String>,
+    #[serde(default)]
+    parent_id: Option<String>,
     chat_id: String,
     chat_type: String,
     message_type: String,
@@ -62,10 +137,23 @@ struct MessageRaw {
 struct TextMessage {
     text: String,
 }
+#[derive(Deserialize)]
+struct ImageMessage {
+    image_key: String,
+}
+#[derive(Deserialize)]
+struct FileMessage {
+    file_key: String,
+}
 
 #[async_trait]
 pub trait Handler {
     async fn handle(&self, event: Event);
+
+    /// How long a single [`handle`](Self::handle) may run before it is aborted and logged, `None` never aborts.
+    fn timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(60))
+    }
 }
 
 pub struct HandlerConfig {
@@ -80,7 +168,7 @@ pub fn handler(config: web::Data<HandlerConfig>) -> Scope {
 }
 
 #[derive(Deserialize)]
-struct EventHeader {
+pub struct EventHeader {
     event_type: String,
     token: String,
 }
@@ -97,8 +185,9 @@ struct Challenge {
     token: String,
 }
 
+/// The body Lark posts to the event url, either a url verification challenge or a v2 event.
 #[derive(Deserialize)]
-struct EventRequest {
+pub struct EventRequest {
     #[serde(flatten)]
     v2: Option<EventV2>,
     #[serde(flatten)]
@@ -106,7 +195,7 @@ struct EventRequest {
 }
 
 #[derive(Serialize)]
-struct EventResponse {
+pub struct EventResponse {
     challenge: String,
 }
 
@@ -119,25 +208,54 @@ impl Responder for Empty {
     }
 }
 
+/// The response to a url verification challenge, `None` if `request` is no challenge or carries another token.
+pub fn verify_challenge(request: &EventRequest, token: &str) -> Option<EventResponse> {
+    request.challenge.as_ref()
+        .filter(|challenge| challenge.token == token)
+        .map(|challenge| EventResponse { challenge: challenge.challenge.clone() })
+}
+
+/// Whether an event was sent with the app's verification token.
+pub fn authenticate_event(header: &EventHeader, token: &str) -> bool {
+    header.token == token
+}
+
+/// Takes the raw body rather than `Json<EventRequest>` so a body that fails to parse is acked with an empty 200,
+/// a 400 would have Lark retry the same event over and over.
 #[post("")]
-async fn handle(config: web::Data<HandlerConfig>, req: Json<EventRequest>) -> Either<Json<EventResponse>, Empty> {
-    if let Some(challenge) = req.0.challenge {
-        if challenge.token == config.verification_token {
-            return Either::Left(Json(EventResponse { challenge: challenge.challenge }));
+async fn handle(config: web::Data<HandlerConfig>, body: web::Bytes) -> Either<Json<EventResponse>, Empty> {
+    let req: EventRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(e) => {
+            // the body is unauthenticated and may hold the verification token, so it is never logged
+            tracing::warn!("LARK_EVENT_MALFORMED: {}, body length: {}", e, body.len());
+            return Either::Right(Empty);
         }
-        return Either::Right(Empty);
+    };
+    if req.challenge.is_some() {
+        return match verify_challenge(&req, &config.verification_token) {
+            Some(resp) => Either::Left(Json(resp)),
+            None => Either::Right(Empty),
+        };
     }
-    if req.v2.is_none() {
+    let Some(event) = req.v2 else {
         return Either::Right(Empty);
-    }
-
-    let event = req.0.v2.unwrap();
-    if event.header.token != config.verification_token {
+    };
+    if !authenticate_event(&event.header, &config.verification_token) {
         return Either::Right(Empty);
     }
     let event = parse_event(&event.header.event_type, event.event);
     if let Some(event) = event {
-        actix_web::rt::spawn(async move { config.handler.handle(event).await });
+        spawn_with_trace(async move {
+            match config.handler.timeout() {
+                Some(timeout) => {
+                    if tokio::time::timeout(timeout, config.handler.handle(event)).await.is_err() {
+                        tracing::error!("LARK_EVENT_HANDLER_TIMEOUT: aborted after {:?}", timeout);
+                    }
+                }
+                None => config.handler.handle(event).await,
+            }
+        });
     }
     Either::Right(Empty)
 }
@@ -145,8 +263,58 @@ async fn handle(config: web::Data<HandlerConfig>, req: Json<EventRequest>) -> Ei
 fn parse_event(r#type: &str, event: serde_json::Value) -> Option<Event> {
     match r#type {
         "im.message.receive_v1" => serde_json::from_value(event).ok()
-            .and_then(|raw| MessageReceiveEvent::from_raw(raw))
-            .map(|event| Event::MessageReceive(event)),
+            .and_then(MessageReceiveEvent::from_raw)
+            .map(Event::MessageReceive),
+        "card.action.trigger" => serde_json::from_value(event).ok()
+            .map(CardActionEvent::from_raw)
+            .map(Event::CardAction),
         _ => None,
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_challenge() {
+        let req: EventRequest = serde_json::from_str(r#"{"challenge":"abc","token":"secret","type":"url_verification"}"#).unwrap();
+
+        assert_eq!(verify_challenge(&req, "secret").unwrap().challenge, "abc");
+        assert!(verify_challenge(&req, "other").is_none());
+    }
+
+    #[test]
+    fn test_verify_challenge_ignores_events() {
+        let req: EventRequest = serde_json::from_str(
+            r#"{"schema":"2.0","header":{"event_type":"im.message.receive_v1","token":"secret"},"event":{}}"#,
+        ).unwrap();
+
+        assert!(verify_challenge(&req, "secret").is_none());
+        let header = &req.v2.as_ref().unwrap().header;
+        assert!(authenticate_event(header, "secret"));
+        assert!(!authenticate_event(header, "other"));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_body_acked() {
+        struct Noop;
+        #[async_trait]
+        impl Handler for Noop {
+            async fn handle(&self, _: Event) {}
+        }
+
+        let config = web::Data::new(HandlerConfig { verification_token: "secret".to_string(), handler: Box::new(Noop) });
+        let app = actix_web::test::init_service(actix_web::App::new().service(web::scope("/lark").service(handler(config)))).await;
+
+        let req = actix_web::test::TestRequest::post().uri("/lark").set_payload("{not json").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/lark")
+            .set_payload(r#"{"challenge":"abc","token":"secret","type":"url_verification"}"#)
+            .to_request();
+        let resp: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp["challenge"], "abc");
+    }
+}
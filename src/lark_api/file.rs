@@ -0,0 +1,65 @@
+use super::{client::{read_json, Client, CommonResp, BASE_URL}, error::Result};
+use bytes::Bytes;
+use reqwest::multipart::{Form, Part};
+use serde::Deserialize;
+use std::sync::LazyLock;
+
+impl Client {
+    /// Uploads a file for use in file messages and returns its file_key. `file_type` is one of
+    /// opus, mp4, pdf, doc, xls, ppt or stream.
+    ///
+    /// There is nothing to deduplicate an upload by, so it is only retried when the request never got through or was rate limited.
+    pub async fn upload_file(&self, name: &str, file_type: &str, bytes: Vec<u8>) -> Result<String> {
+        static URL: LazyLock<url::Url> = LazyLock::new(|| {
+            BASE_URL.join("im/v1/files").unwrap()
+        });
+        #[derive(Deserialize)]
+        struct Response {
+            #[serde(flatten)]
+            common_resp: CommonResp,
+            data: Option<Data>,
+        }
+        #[derive(Deserialize)]
+        struct Data {
+            file_key: String,
+        }
+
+        self.with_non_idempotent_retry_policy(|| async {
+            let form = Form::new()
+                .text("file_type", file_type.to_string())
+                .text("file_name", name.to_string())
+                .part("file", Part::bytes(bytes.clone()).file_name(name.to_string()));
+            let token = self.token().await?;
+            self.throttle().await;
+            let resp = self.client.post(URL.clone())
+                .header("Authorization", token)
+                .multipart(form)
+                .send()
+                .await?;
+            let resp: Response = read_json(resp).await?;
+            match resp.data {
+                Some(data) if resp.common_resp.code == 0 => Ok(data.file_key),
+                _ => Err(resp.common_resp.into()),
+            }
+        }).await
+    }
+
+    /// Downloads a file uploaded by this app.
+    pub async fn get_file(&self, file_key: &str) -> Result<Bytes> {
+        let url = BASE_URL.join(&format!("im/v1/files/{}", file_key))?;
+
+        self.with_retry_policy(|| async {
+            let token = self.token().await?;
+            self.throttle().await;
+            let resp = self.client.get(url.clone())
+                .header("Authorization", token)
+                .send()
+                .await?;
+            if !resp.status().is_success() {
+                let resp: CommonResp = read_json(resp).await?;
+                return Err(resp.into());
+            }
+            Ok(resp.bytes().await?)
+        }).await
+    }
+}
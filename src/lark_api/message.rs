@@ -1,4 +1,6 @@
-use super::{client::{Client, CommonResp, BASE_URL}, error::{Error, Result}};
+use super::{client::{read_json, Client, CommonResp, BASE_URL}, error::{Error, Result}};
+use crate::util::{id_gen::gen_id, radix32::radix_32};
+use futures_util::{stream, StreamExt as _};
 use serde::{Deserialize, Serialize};
 use std::sync::LazyLock;
 
@@ -8,6 +10,9 @@ pub struct SendMessageRequest {
     receive_id: String,
     msg_type: &'static str,
     content: String,
+    /// Lark sends a message once however many requests carry its uuid within an hour, so retries of a
+    /// request that did get through don't deliver it again.
+    uuid: String,
 }
 
 impl SendMessageRequest {
@@ -16,6 +21,40 @@ impl SendMessageRequest {
     }
 }
 
+/// A piece of a text message, see [`SendMessageRequestBuilder::text_with_mentions`].
+pub enum TextSegment<'a> {
+    Text(&'a str),
+    /// Mentions the user with the given open_id, `"all"` mentions everyone in the chat.
+    Mention(&'a str),
+}
+
+fn escape_text(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn render_segments(segments: &[TextSegment]) -> String {
+    let mut text = String::new();
+    for segment in segments {
+        match segment {
+            TextSegment::Text(s) => escape_text(s, &mut text),
+            TextSegment::Mention(user_id) => {
+                text.push_str("<at user_id=\"");
+                escape_text(user_id, &mut text);
+                text.push_str("\"></at>");
+            }
+        }
+    }
+    text
+}
+
 #[derive(Default)]
 pub struct SendMessageRequestBuilder {
     receive_id_type: Option<&'static str>,
@@ -42,12 +81,31 @@ impl SendMessageRequestBuilder {
         })).unwrap());
         self
     }
+    /// Like [`text`](Self::text) but with `@user` mentions, literal text is escaped so it can't inject markup.
+    pub fn text_with_mentions(self, segments: &[TextSegment]) -> Self {
+        self.text(&render_segments(segments))
+    }
+    /// Sends an interactive card, it can later be replaced with [`Client::update_message`].
+    pub fn card(mut self, card: &serde_json::Value) -> Self {
+        self.msg_type = Some("interactive");
+        self.content = Some(card.to_string());
+        self
+    }
+    /// Sends a file previously uploaded with [`Client::upload_file`].
+    pub fn file(mut self, file_key: &str) -> Self {
+        self.msg_type = Some("file");
+        self.content = Some(serde_json::to_string(&serde_json::json!({
+            "file_key": file_key,
+        })).unwrap());
+        self
+    }
     pub fn build(self) -> Result<SendMessageRequest> {
         Ok(SendMessageRequest {
             receive_id_type: self.receive_id_type.ok_or(Error::MissingRequestParam("receive_id_type".to_string()))?,
             receive_id: self.receive_id.ok_or(Error::MissingRequestParam("receive_id".to_string()))?,
             msg_type: self.msg_type.ok_or(Error::MissingRequestParam("msg_type".to_string()))?,
             content: self.content.ok_or(Error::MissingRequestParam("content".to_string()))?,
+            uuid: radix_32(gen_id()).to_string(),
         })
     }
 }
@@ -56,25 +114,169 @@ impl SendMessageRequestBuilder {
 pub struct SendMessageResponse {
     #[serde(flatten)]
     common_resp: CommonResp,
+    data: Option<MessageData>,
+}
+
+#[derive(Deserialize)]
+struct MessageData {
+    message_id: String,
+    create_time: Option<String>,
+}
+
+impl SendMessageResponse {
+    pub fn message_id(&self) -> Option<&str> {
+        self.data.as_ref().map(|data| data.message_id.as_str())
+    }
+
+    /// Millisecond timestamp of when the message was created.
+    pub fn create_time(&self) -> Option<&str> {
+        self.data.as_ref().and_then(|data| data.create_time.as_deref())
+    }
 }
 
 impl Client {
     pub async fn send_message(&self, req: SendMessageRequest) -> Result<SendMessageResponse> {
-        const URL: LazyLock<url::Url> = LazyLock::new(|| {
+        static URL: LazyLock<url::Url> = LazyLock::new(|| {
             BASE_URL.join("im/v1/messages").unwrap()
         });
 
-        let token = self.get_token().await?;
-        let resp = self.client.post(URL.clone())
-            .header("Authorization", token)
-            .query(&[("receive_id_type", req.receive_id_type)])
-            .json(&req)
-            .send()
-            .await?;
-        let resp: SendMessageResponse = resp.json().await?;
-        if resp.common_resp.code != 0 {
-            return Err(resp.common_resp.into());
+        self.with_retry_policy(|| async {
+            let token = self.token().await?;
+            self.throttle().await;
+            let resp = self.client.post(URL.clone())
+                .header("Authorization", token)
+                .query(&[("receive_id_type", req.receive_id_type)])
+                .json(&req)
+                .send()
+                .await?;
+            let resp: SendMessageResponse = read_json(resp).await?;
+            if resp.common_resp.code != 0 {
+                return Err(resp.common_resp.into());
+            }
+            Ok(resp)
+        }).await
+    }
+
+    /// Sends each request with at most `concurrency` in flight, returning the results in request order.
+    /// One failed request doesn't stop the others, they all share one cached token and the client's rate limit.
+    pub async fn send_messages(&self, requests: Vec<SendMessageRequest>, concurrency: usize) -> Vec<Result<SendMessageResponse>> {
+        stream::iter(requests)
+            .map(|req| self.send_message(req))
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Replaces the content of a sent interactive card and returns its message id.
+    pub async fn update_message(&self, message_id: &str, card: &serde_json::Value) -> Result<String> {
+        #[derive(Serialize)]
+        struct Request {
+            content: String,
+        }
+
+        let url = BASE_URL.join(&format!("im/v1/messages/{}", message_id))?;
+
+        self.with_retry_policy(|| async {
+            let token = self.token().await?;
+            self.throttle().await;
+            let resp = self.client.patch(url.clone())
+                .header("Authorization", token)
+                .json(&Request { content: card.to_string() })
+                .send()
+                .await?;
+            let resp: CommonResp = read_json(resp).await?;
+            if resp.code != 0 {
+                return Err(resp.into());
+            }
+            Ok(message_id.to_string())
+        }).await
+    }
+
+    /// Sends an interactive card, the only content Lark allows here, that only `open_id` sees in the chat.
+    /// Returns the message id. There is no uuid to deduplicate by, so only failures before the request got
+    /// through are retried.
+    pub async fn send_ephemeral(&self, chat_id: &str, open_id: &str, card: &serde_json::Value) -> Result<String> {
+        static URL: LazyLock<url::Url> = LazyLock::new(|| {
+            BASE_URL.join("ephemeral/v1/send").unwrap()
+        });
+        #[derive(Serialize)]
+        struct Request<'a> {
+            chat_id: &'a str,
+            open_id: &'a str,
+            msg_type: &'static str,
+            card: &'a serde_json::Value,
+        }
+        #[derive(Deserialize)]
+        struct Response {
+            #[serde(flatten)]
+            common_resp: CommonResp,
+            data: Option<Data>,
         }
-        Ok(resp)
+        #[derive(Deserialize)]
+        struct Data {
+            message_id: String,
+        }
+
+        self.with_non_idempotent_retry_policy(|| async {
+            let token = self.token().await?;
+            self.throttle().await;
+            let resp = self.client.post(URL.clone())
+                .header("Authorization", token)
+                .json(&Request {
+                    chat_id,
+                    open_id,
+                    msg_type: "interactive",
+                    card,
+                })
+                .send()
+                .await?;
+            let resp: Response = read_json(resp).await?;
+            match resp.data {
+                Some(data) if resp.common_resp.code == 0 => Ok(data.message_id),
+                _ => Err(resp.common_resp.into()),
+            }
+        }).await
+    }
+
+    pub async fn recall_message(&self, message_id: &str) -> Result<()> {
+        let url = BASE_URL.join(&format!("im/v1/messages/{}", message_id))?;
+
+        self.with_retry_policy(|| async {
+            let token = self.token().await?;
+            self.throttle().await;
+            let resp = self.client.delete(url.clone())
+                .header("Authorization", token)
+                .send()
+                .await?;
+            let resp: CommonResp = read_json(resp).await?;
+            if resp.code != 0 {
+                return Err(resp.into());
+            }
+            Ok(())
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_segments_escapes_text() {
+        let text = render_segments(&[
+            TextSegment::Text("hi "),
+            TextSegment::Mention("ou_123"),
+            TextSegment::Text(" <at user_id=\"all\"></at> & bye"),
+        ]);
+        assert_eq!(text, "hi <at user_id=\"ou_123\"></at> &lt;at user_id=&quot;all&quot;&gt;&lt;/at&gt; &amp; bye");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_requests_carry_their_own_uuid() {
+        let build = || SendMessageRequest::builder().receiver_chat_id("oc_1".to_string()).text("hi").build().unwrap();
+        let (a, b) = (build(), build());
+        assert!(!a.uuid.is_empty() && a.uuid.len() <= 50);
+        assert_ne!(a.uuid, b.uuid);
+        assert_eq!(serde_json::to_value(&a).unwrap()["uuid"], a.uuid.as_str());
+    }
+}
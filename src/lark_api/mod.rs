@@ -1,9 +1,17 @@
+mod chat;
 mod client;
+mod contact;
 mod error;
+mod file;
 mod message;
+mod page;
+#[cfg(feature = "lark-api-etcd")]
+mod token_cache;
 #[cfg(feature = "lark-api-event")]
 pub mod event;
 
-pub use client::{Client, CommonResp};
+pub use client::{Client, CommonResp, RetryPolicy, TokenKind};
 pub use error::Error;
+pub use chat::Member;
+pub use contact::UserInfo;
 pub use message::*;
@@ -0,0 +1,39 @@
+use super::error::{Error, Result};
+use futures_util::{stream, Stream, TryStreamExt as _};
+use serde::Deserialize;
+use std::future::Future;
+
+/// One page of a Lark list endpoint's `data`.
+#[derive(Deserialize)]
+pub(super) struct Page<T> {
+    // a plain `default` would require `T: Default`
+    #[serde(default = "Vec::new")]
+    pub(super) items: Vec<T>,
+    pub(super) page_token: Option<String>,
+    #[serde(default)]
+    pub(super) has_more: bool,
+}
+
+/// Streams the items of every page, `fetch` gets the page token, `None` for the first page,
+/// and is only called again once the previous page's items are consumed.
+pub(super) fn paginate<'a, T, F, Fut>(fetch: F) -> impl Stream<Item=Result<T>> + 'a
+where
+    T: 'a,
+    F: Fn(Option<String>) -> Fut + 'a,
+    Fut: Future<Output=Result<Page<T>>> + 'a,
+{
+    stream::try_unfold(Some(None), move |page_token: Option<Option<String>>| {
+        let page = page_token.map(&fetch);
+        async move {
+            let Some(page) = page else {
+                return Ok(None);
+            };
+            let page = page.await?;
+            let next = match page.page_token {
+                Some(page_token) if page.has_more && !page_token.is_empty() => Some(Some(page_token)),
+                _ => None,
+            };
+            Ok::<_, Error>(Some((stream::iter(page.items.into_iter().map(Ok)), next)))
+        }
+    }).try_flatten()
+}
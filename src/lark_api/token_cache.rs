@@ -0,0 +1,85 @@
+use super::error::Result;
+use etcd_client::{Client, LockOptions};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// bounds how long a crashed replica can keep the others waiting on the refresh lock
+const LOCK_TTL: i64 = 10;
+
+#[derive(Serialize, Deserialize)]
+struct CachedToken {
+    token: String,
+    /// Unix seconds after which the token must not be used.
+    expires_at: u64,
+}
+
+/// Tokens shared by all replicas of an app under `<prefix>/<app_id>/<kind>`.
+pub(super) struct EtcdTokenCache {
+    client: Client,
+    prefix: String,
+}
+
+impl EtcdTokenCache {
+    pub(super) fn new(client: Client, prefix: &str) -> Self {
+        Self { client, prefix: prefix.trim_end_matches('/').to_string() }
+    }
+
+    /// Returns the shared token and how long it stays valid, refreshing it with `fetch` under an etcd lock
+    /// when missing or expired. Any etcd failure falls back to `fetch` alone, as without the cache.
+    pub(super) async fn get_or_refresh<F, Fut>(&self, app_id: &str, kind: &str, fetch: F) -> Result<(String, Duration)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output=Result<(String, Duration)>>,
+    {
+        let key = format!("{}/{}/{}", self.prefix, app_id, kind);
+        let mut client = self.client.clone();
+        if let Ok(Some(cached)) = read(&mut client, &key).await {
+            return Ok(cached);
+        }
+
+        let Ok(lease) = client.lease_grant(LOCK_TTL, None).await else {
+            return fetch().await;
+        };
+        let lock = client.lock(format!("{}.lock", key), Some(LockOptions::new().with_lease(lease.id()))).await;
+        let result = match lock {
+            Ok(_) => match read(&mut client, &key).await {
+                // refreshed by the replica holding the lock before us
+                Ok(Some(cached)) => Ok(cached),
+                _ => {
+                    let result = fetch().await;
+                    if let Ok((token, valid_for)) = &result {
+                        let cached = CachedToken {
+                            token: token.clone(),
+                            expires_at: unix_now() + valid_for.as_secs(),
+                        };
+                        if let Ok(value) = serde_json::to_string(&cached) {
+                            let _ = client.put(key.as_str(), value, None).await;
+                        }
+                    }
+                    result
+                }
+            },
+            Err(_) => fetch().await,
+        };
+        // revoking the lease releases the lock too
+        let _ = client.lease_revoke(lease.id()).await;
+        result
+    }
+}
+
+async fn read(client: &mut Client, key: &str) -> std::result::Result<Option<(String, Duration)>, etcd_client::Error> {
+    let resp = client.get(key, None).await?;
+    let cached = resp.kvs().first()
+        .and_then(|kv| serde_json::from_slice::<CachedToken>(kv.value()).ok());
+    Ok(cached.and_then(|cached| {
+        let valid_for = cached.expires_at.checked_sub(unix_now()).filter(|secs| *secs > 0)?;
+        Some((cached.token, Duration::from_secs(valid_for)))
+    }))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -13,6 +13,9 @@ pub mod util;
     feature = "http-client",
     feature = "http-server-tracer",
     feature = "http-jwt",
+    feature = "http-health",
+    feature = "http-cors",
+    feature = "http-timeout",
 ))]
 pub mod http;
 
@@ -1,8 +1,10 @@
-use crate::util::{env, id_gen::gen_id, radix32::radix_32};
+use crate::util::{env, id_gen::gen_id, radix32::{from_radix_32, radix_32}};
 use serde_json::json;
 use std::{
     collections::{BTreeMap, HashMap},
     fmt::Debug,
+    future::Future,
+    io::Write,
     str::FromStr,
     sync::{LazyLock, Mutex},
     time::{Duration, Instant},
@@ -10,11 +12,11 @@ use std::{
 use tracing::{
     field::Field,
     span::{Attributes, Record},
-    Event, Id, Subscriber,
+    Event, Id, Instrument, Level, Metadata, Subscriber,
 };
 use tracing_subscriber::{
     field::Visit,
-    fmt::format::FmtSpan,
+    fmt::{format::{FmtSpan, Writer}, time::{FormatTime as _, SystemTime}, MakeWriter},
     layer::{Context, SubscriberExt},
     util::SubscriberInitExt,
     EnvFilter,
@@ -30,32 +32,367 @@ pub fn setup(filter: &str) {
     }
 }
 
+/// Where the dev and simple setups write their log lines.
+#[derive(Copy, Clone, Default)]
+pub enum Output {
+    #[default]
+    Stdout,
+    /// Keeps stdout free for a program's own machine-readable output.
+    Stderr,
+}
+
+impl Output {
+    fn make_writer(self) -> tracing_subscriber::fmt::writer::BoxMakeWriter {
+        use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+        match self {
+            Output::Stdout => BoxMakeWriter::new(std::io::stdout),
+            Output::Stderr => BoxMakeWriter::new(std::io::stderr),
+        }
+    }
+}
+
 pub fn setup_dev(filter: &str) {
+    setup_dev_to(filter, Output::Stdout)
+}
+
+pub fn setup_dev_to(filter: &str, output: Output) {
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_str(filter).expect("invalid filter"))
+        .with_writer(output.make_writer())
         .pretty()
         .with_span_events(FmtSpan::CLOSE)
         .init()
 }
 
 pub fn setup_cloud_native(filter: &str) {
+    setup_cloud_native_with(filter, CloudNativeConfig::default())
+}
+
+pub fn setup_cloud_native_with(filter: &str, config: CloudNativeConfig) {
+    tracing_subscriber::registry()
+        .with(EnvFilter::from_str(filter).expect("invalid filter"))
+        .with(CloudNativeLayer(config, std::io::stdout))
+        .init();
+}
+
+/// Like [`setup_cloud_native`] but writes the same JSON lines to `{dir}/{prefix}.{date}.log`, rotated daily.
+/// Lines are written by a background thread, keep the returned guard alive until exit or buffered lines are lost.
+#[cfg(feature = "tracing-file")]
+pub fn setup_cloud_native_file(
+    filter: &str,
+    dir: impl AsRef<std::path::Path>,
+    prefix: &str,
+) -> tracing_appender::non_blocking::WorkerGuard {
+    setup_cloud_native_file_with(filter, dir, prefix, CloudNativeConfig::default())
+}
+
+#[cfg(feature = "tracing-file")]
+pub fn setup_cloud_native_file_with(
+    filter: &str,
+    dir: impl AsRef<std::path::Path>,
+    prefix: &str,
+    config: CloudNativeConfig,
+) -> tracing_appender::non_blocking::WorkerGuard {
+    setup_cloud_native_file_buffered(filter, dir, prefix, config, FileBuffer::default())
+}
+
+/// The queue between the logging threads and the file writer thread.
+#[cfg(feature = "tracing-file")]
+#[derive(Copy, Clone)]
+pub struct FileBuffer {
+    /// Lines queued before `overflow` applies, 128 000 by default.
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+/// What happens to a line logged while the [`FileBuffer`] is full.
+#[cfg(feature = "tracing-file")]
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Waits for room, no line is lost but logging slows down to the disk's pace. The default.
+    #[default]
+    Block,
+    /// Drops the line, a `logs_dropped` warning reports the count every 10 seconds while lines are dropped.
+    /// The warning is written to the file directly, so neither the full buffer nor the filter can drop it.
+    Drop,
+}
+
+#[cfg(feature = "tracing-file")]
+impl Default for FileBuffer {
+    fn default() -> Self {
+        Self {
+            capacity: tracing_appender::non_blocking::DEFAULT_BUFFERED_LINES_LIMIT,
+            overflow: Overflow::Block,
+        }
+    }
+}
+
+#[cfg(feature = "tracing-file")]
+pub fn setup_cloud_native_file_buffered(
+    filter: &str,
+    dir: impl AsRef<std::path::Path>,
+    prefix: &str,
+    config: CloudNativeConfig,
+    buffer: FileBuffer,
+) -> tracing_appender::non_blocking::WorkerGuard {
+    use tracing_appender::{non_blocking::NonBlockingBuilder, rolling::{RollingFileAppender, Rotation}};
+
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(prefix)
+        .filename_suffix("log")
+        .build(dir)
+        .expect("failed to create log file");
+    let appender = SharedAppender(std::sync::Arc::new(appender));
+    let (writer, guard) = NonBlockingBuilder::default()
+        .buffered_lines_limit(buffer.capacity)
+        .lossy(buffer.overflow == Overflow::Drop)
+        .finish(appender.clone());
+    if buffer.overflow == Overflow::Drop {
+        let dropped = writer.error_counter();
+        // written straight to the file, past the full queue and the filter, which would drop the report too
+        let reporter = CloudNativeLayer(config, appender);
+        // lives as long as the process, like the global subscriber it reports on
+        std::thread::spawn(move || {
+            let mut reported = 0;
+            loop {
+                std::thread::sleep(Duration::from_secs(10));
+                let total = dropped.dropped_lines();
+                if total > reported {
+                    reporter.write_logs_dropped(total - reported);
+                    reported = total;
+                }
+            }
+        });
+    }
     tracing_subscriber::registry()
         .with(EnvFilter::from_str(filter).expect("invalid filter"))
-        .with(CloudNativeLayer)
+        .with(CloudNativeLayer(config, writer))
         .init();
+    guard
+}
+
+/// The file appender shared by the non-blocking worker and the drop reporter, every write is one whole line
+/// and the file is opened for appending, so their lines don't interleave.
+#[cfg(feature = "tracing-file")]
+#[derive(Clone)]
+struct SharedAppender(std::sync::Arc<tracing_appender::rolling::RollingFileAppender>);
+
+#[cfg(feature = "tracing-file")]
+impl Write for SharedAppender {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.make_writer().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.make_writer().flush()
+    }
+}
+
+#[cfg(feature = "tracing-file")]
+impl<'a> MakeWriter<'a> for SharedAppender {
+    type Writer = tracing_appender::rolling::RollingWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.0.make_writer()
+    }
 }
 
 pub fn setup_simple(filter: &str) {
+    setup_simple_to(filter, Output::Stdout)
+}
+
+pub fn setup_simple_to(filter: &str, output: Output) {
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_str(filter).expect("invalid filter"))
+        .with_writer(output.make_writer())
         .with_span_events(FmtSpan::CLOSE)
         .init()
 }
 
-struct CloudNativeLayer;
-impl<S> tracing_subscriber::Layer<S> for CloudNativeLayer
+#[derive(Copy, Clone)]
+pub struct CloudNativeConfig {
+    /// Probability that a root span starting a new trace is logged, children follow their root.
+    /// Spans continuing an incoming trace id are always logged.
+    pub sample_rate: f64,
+    /// Records each span's busy time into the `span_busy_seconds` histogram, labelled by span name.
+    #[cfg(feature = "tracing-metrics")]
+    pub busy_time_histogram: bool,
+    /// Writes indented JSON for humans instead of one record per line, collectors need the compact form.
+    pub pretty: bool,
+    /// Adds a `severity` field mapped from the level, `level` is still written as is.
+    pub severity: Option<Severity>,
+    /// Most fields kept per span or event, later ones are dropped and counted in `_fields_truncated`.
+    /// `None` keeps every field.
+    pub max_fields: Option<usize>,
+    pub schema: Schema,
+    pub integers: Integers,
+    /// Also writes a `span_start` record when a span at this level or more severe is created, so slow or hung
+    /// operations show up before they complete. The full `span` record is still written on close.
+    pub span_start: Option<Level>,
+    /// Adds a `version` key to every record, to tell deploys apart. Defaults to the `BUILD_VERSION` env var,
+    /// read once when the first default config is built, and to no key when it is unset or empty. Override it
+    /// from the binary, e.g. `Some(env!("CARGO_PKG_VERSION"))`, as the crate's own `CARGO_PKG_VERSION` is
+    /// this library's.
+    pub version: Option<&'static str>,
+}
+
+/// How integer fields are written. JSON numbers are exact in Rust, but JavaScript based viewers parse them as
+/// doubles and silently round anything beyond 2^53, garbling ids. Strings are exact everywhere, at the cost of
+/// numeric queries in the collector. `u128`/`i128` fields are always strings.
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+pub enum Integers {
+    #[default]
+    Numbers,
+    /// Numbers when a double holds them exactly, strings beyond ±(2^53 - 1).
+    SafeNumbers,
+    /// Every `u64`/`i64` as a string.
+    Strings,
+}
+
+impl Integers {
+    const MAX_SAFE: u64 = (1 << 53) - 1;
+
+    fn of<N>(self, value: N) -> serde_json::Value
+    where
+        N: Into<serde_json::Value> + ToString + TryInto<i64> + Copy,
+    {
+        let safe = || value.try_into().is_ok_and(|n: i64| n.unsigned_abs() <= Self::MAX_SAFE);
+        match self {
+            Integers::Numbers => value.into(),
+            Integers::SafeNumbers if safe() => value.into(),
+            _ => value.to_string().into(),
+        }
+    }
+}
+
+/// Field layout of the JSON records.
+#[derive(Copy, Clone, Default)]
+pub enum Schema {
+    /// The crate's own flat layout.
+    #[default]
+    Flat,
+    /// Elastic Common Schema, with dotted keys as in the ECS logging spec. The flat fields are renamed:
+    ///
+    /// | flat             | ECS                                 |
+    /// |------------------|-------------------------------------|
+    /// | `level`          | `log.level`                         |
+    /// | `target`         | `log.logger`                        |
+    /// | `file`, `line`   | `log.origin.file.name`, `.line`     |
+    /// | `name`           | `event.action`                      |
+    /// | `trace_id`       | `trace.id`                          |
+    /// | `span_id`        | `span.id`                           |
+    /// | `fields.message` | `message`                           |
+    /// | `version`        | `service.version`                   |
+    ///
+    /// `@timestamp`, `ecs.version` and `event.dataset` are added, spans also get `event.duration` in
+    /// nanoseconds. Other keys, such as `type`, `parent_id` and `busy_time`, are kept as they are.
+    Ecs {
+        dataset: &'static str,
+    },
+}
+
+const ECS_VERSION: &str = "8.11.0";
+
+impl Schema {
+    fn apply(self, mut obj: serde_json::Map<String, serde_json::Value>) -> serde_json::Map<String, serde_json::Value> {
+        let Schema::Ecs { dataset } = self else {
+            return obj;
+        };
+        let mut ecs = serde_json::Map::new();
+        let mut timestamp = String::new();
+        let _ = SystemTime.format_time(&mut Writer::new(&mut timestamp));
+        ecs.insert("@timestamp".into(), timestamp.into());
+        ecs.insert("ecs.version".into(), ECS_VERSION.into());
+        ecs.insert("event.dataset".into(), dataset.into());
+        if let Some(message) = obj.get_mut("fields")
+            .and_then(serde_json::Value::as_object_mut)
+            .and_then(|fields| fields.remove("message")) {
+            ecs.insert("message".into(), message);
+        }
+        for (flat, renamed) in [
+            ("level", "log.level"),
+            ("target", "log.logger"),
+            ("file", "log.origin.file.name"),
+            ("line", "log.origin.file.line"),
+            ("name", "event.action"),
+            ("trace_id", "trace.id"),
+            ("span_id", "span.id"),
+            ("version", "service.version"),
+        ] {
+            if let Some(value) = obj.remove(flat) {
+                ecs.insert(renamed.into(), value);
+            }
+        }
+        ecs.extend(obj);
+        ecs
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum Severity {
+    /// Google Cloud Logging severities: `ERROR`, `WARNING`, `INFO` and `DEBUG`.
+    Gcp,
+    /// Numeric syslog severities, from 3 for errors to 7 for debug and trace.
+    Syslog,
+}
+
+impl Severity {
+    fn of(self, level: &Level) -> serde_json::Value {
+        match self {
+            Severity::Gcp => match *level {
+                Level::ERROR => "ERROR",
+                Level::WARN => "WARNING",
+                Level::INFO => "INFO",
+                _ => "DEBUG",
+            }.into(),
+            Severity::Syslog => match *level {
+                Level::ERROR => 3,
+                Level::WARN => 4,
+                Level::INFO => 6,
+                _ => 7,
+            }.into(),
+        }
+    }
+}
+
+impl CloudNativeConfig {
+    fn serialize(&self, obj: &serde_json::Map<String, serde_json::Value>) -> serde_json::Result<String> {
+        if self.pretty {
+            serde_json::to_string_pretty(obj)
+        } else {
+            serde_json::to_string(obj)
+        }
+    }
+}
+
+static BUILD_VERSION: LazyLock<Option<String>> = LazyLock::new(|| {
+    std::env::var("BUILD_VERSION").ok().filter(|version| !version.is_empty())
+});
+
+impl Default for CloudNativeConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 1.0,
+            #[cfg(feature = "tracing-metrics")]
+            busy_time_histogram: false,
+            pretty: false,
+            severity: None,
+            max_fields: None,
+            schema: Schema::Flat,
+            integers: Integers::Numbers,
+            span_start: None,
+            version: BUILD_VERSION.as_deref(),
+        }
+    }
+}
+
+pub(crate) struct CloudNativeLayer<W>(pub(crate) CloudNativeConfig, pub(crate) W);
+impl<S, W> tracing_subscriber::Layer<S> for CloudNativeLayer<W>
 where
     S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + 'static,
 {
     fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
         let mut span_scope = ctx.span_scope(id).unwrap();
@@ -63,17 +400,21 @@ where
         let parent_span = span_scope.next();
 
         let mut fields = BTreeMap::new();
-        let mut visitor = JsonVisitor(&mut fields, 0);
+        let mut visitor = JsonVisitor::new(&mut fields, &self.0);
         attrs.record(&mut visitor);
+        let fields_truncated = visitor.truncated;
 
         let trace_id: u128;
         let parent_id: Option<u128>;
+        let sampled: bool;
         match parent_span {
             None => {
-                if visitor.1 != 0 {
-                    trace_id = visitor.1;
+                if visitor.trace_id != 0 {
+                    trace_id = visitor.trace_id;
+                    sampled = true;
                 } else {
                     trace_id = gen_id();
+                    sampled = should_sample(trace_id, self.0.sample_rate);
                 }
                 parent_id = None;
             }
@@ -82,6 +423,7 @@ where
                 let storage = extensions.get::<Storage>().unwrap();
                 trace_id = storage.trace_id;
                 parent_id = Some(storage.span_id);
+                sampled = storage.sampled;
             }
         }
 
@@ -89,12 +431,18 @@ where
             trace_id,
             span_id: gen_id(),
             parent_id,
+            sampled,
             created_at: Instant::now(),
             enter_at: None,
             busy_time: Duration::default(),
             fields,
+            fields_truncated,
         };
 
+        if storage.sampled && self.0.span_start.is_some_and(|level| *span.metadata().level() <= level) {
+            self.write_span_start(span.metadata(), &storage);
+        }
+
         let mut extentions = span.extensions_mut();
         extentions.insert(storage);
         insert_trace_id(id, trace_id);
@@ -104,18 +452,24 @@ where
         let span = ctx.span(span).unwrap();
         let mut extensions = span.extensions_mut();
         let storage = extensions.get_mut::<Storage>().unwrap();
-        let mut visitor = JsonVisitor(&mut storage.fields, 0);
+        let mut visitor = JsonVisitor::new(&mut storage.fields, &self.0);
         values.record(&mut visitor);
+        storage.fields_truncated += visitor.truncated;
     }
 
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
         let mut fields = BTreeMap::new();
-        let mut visitor = JsonVisitor(&mut fields, 0);
+        let mut visitor = JsonVisitor::new(&mut fields, &self.0);
         event.record(&mut visitor);
+        if visitor.truncated > 0 {
+            let truncated = visitor.truncated;
+            fields.insert("_fields_truncated", truncated.into());
+        }
 
         let mut obj: serde_json::map::Map<String, serde_json::Value>;
         match json!({
             "type": "event",
+            "name": event.metadata().name(),
             "level": event.metadata().level().as_str(),
             "fields": fields,
             "target": event.metadata().target(),
@@ -126,16 +480,21 @@ where
             _ => { panic!("event value is not Object") }
         };
 
-        ctx.event_span(event).map(|span| {
+        if let Some(severity) = self.0.severity {
+            obj.insert("severity".into(), severity.of(event.metadata().level()));
+        }
+        if let Some(span) = ctx.event_span(event) {
             let extensions = span.extensions();
             let storage = extensions.get::<Storage>().unwrap();
             obj.insert("trace_id".into(), format!("{}", radix_32(storage.trace_id)).into());
             obj.insert("span_id".into(), format!("{}", radix_32(storage.span_id)).into());
-        });
+        }
 
-        println!("{}", serde_json::to_string(&obj).unwrap_or_else(|e| {
-            format!("failed to serialize event, error: {}, name: {}", e, event.metadata().name())
-        }))
+        if let Some(version) = self.0.version {
+            obj.insert("version".into(), version.into());
+        }
+        let obj = self.0.schema.apply(obj);
+        self.write_record(&obj, "event", event.metadata().name());
     }
 
     fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
@@ -156,8 +515,18 @@ where
         let span = ctx.span(&id).unwrap();
         let extensions = span.extensions();
         let storage = extensions.get::<Storage>().unwrap();
+        #[cfg(feature = "tracing-metrics")]
+        if self.0.busy_time_histogram {
+            metrics::histogram!("span_busy_seconds", "name" => span.metadata().name())
+                .record(storage.busy_time.as_secs_f64());
+        }
+        if !storage.sampled {
+            remove_trace_id(&id);
+            return;
+        }
 
-        let idle_time = storage.created_at.elapsed() - storage.busy_time;
+        // busy time is summed from separate enter/exit readings and can overshoot the elapsed time
+        let idle_time = storage.created_at.elapsed().saturating_sub(storage.busy_time);
         let mut obj: serde_json::map::Map<String, serde_json::Value>;
         match json!({
             "type": "span",
@@ -175,61 +544,184 @@ where
             serde_json::Value::Object(o) => { obj = o }
             _ => { panic!("span value is not Object") }
         }
-        storage.parent_id.map(|parent_id| {
+        if let Some(severity) = self.0.severity {
+            obj.insert("severity".into(), severity.of(span.metadata().level()));
+        }
+        if let Some(parent_id) = storage.parent_id {
             obj.insert("parent_id".into(), format!("{}", radix_32(parent_id)).into());
-        });
-        println!("{}", serde_json::to_string(&obj).unwrap_or_else(|e| {
-            format!("failed to serialize span, error: {}, name: {}", e, span.metadata().name())
-        }));
+        }
+        if storage.fields_truncated > 0 {
+            obj["fields"]["_fields_truncated"] = storage.fields_truncated.into();
+        }
+        if let Schema::Ecs { .. } = self.0.schema {
+            obj.insert("event.duration".into(), (storage.created_at.elapsed().as_nanos() as u64).into());
+        }
+        if let Some(version) = self.0.version {
+            obj.insert("version".into(), version.into());
+        }
+        let obj = self.0.schema.apply(obj);
+        self.write_record(&obj, "span", span.metadata().name());
         remove_trace_id(&id);
     }
 }
 
+impl<W> CloudNativeLayer<W>
+where
+    W: for<'a> MakeWriter<'a> + 'static,
+{
+    // one `write_all` of the record and its newline, a non-blocking writer sends every write as its own
+    // message, so records from other threads could land between the payload and the newline
+    fn write_record(&self, obj: &serde_json::Map<String, serde_json::Value>, kind: &str, name: &str) {
+        let mut line = self.0.serialize(obj).unwrap_or_else(|e| {
+            format!("failed to serialize {}, error: {}, name: {}", kind, e, name)
+        });
+        line.push('\n');
+        let _ = self.1.make_writer().write_all(line.as_bytes());
+    }
+
+    fn write_span_start(&self, metadata: &Metadata<'_>, storage: &Storage) {
+        let mut obj: serde_json::map::Map<String, serde_json::Value>;
+        match json!({
+            "type": "span_start",
+            "name": metadata.name(),
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "file": metadata.file(),
+            "line": metadata.line(),
+            "fields": storage.fields,
+            "trace_id": format!("{}", radix_32(storage.trace_id)),
+            "span_id": format!("{}", radix_32(storage.span_id)),
+        }) {
+            serde_json::Value::Object(o) => { obj = o }
+            _ => { panic!("span value is not Object") }
+        }
+        if let Some(severity) = self.0.severity {
+            obj.insert("severity".into(), severity.of(metadata.level()));
+        }
+        if let Some(parent_id) = storage.parent_id {
+            obj.insert("parent_id".into(), format!("{}", radix_32(parent_id)).into());
+        }
+        if storage.fields_truncated > 0 {
+            obj["fields"]["_fields_truncated"] = storage.fields_truncated.into();
+        }
+        if let Some(version) = self.0.version {
+            obj.insert("version".into(), version.into());
+        }
+        let obj = self.0.schema.apply(obj);
+        self.write_record(&obj, "span start", metadata.name());
+    }
+
+    /// Writes the `logs_dropped` warning as an event record of its own, outside of any subscriber.
+    #[cfg(feature = "tracing-file")]
+    fn write_logs_dropped(&self, dropped: usize) {
+        let mut obj: serde_json::map::Map<String, serde_json::Value>;
+        match json!({
+            "type": "event",
+            "name": "logs_dropped",
+            "level": Level::WARN.as_str(),
+            "fields": {
+                "message": "log lines dropped, the file buffer is full",
+                "logs_dropped": self.0.integers.of(dropped),
+            },
+            "target": module_path!(),
+            "file": file!(),
+            "line": line!(),
+        }) {
+            serde_json::Value::Object(o) => { obj = o }
+            _ => { panic!("event value is not Object") }
+        }
+        if let Some(severity) = self.0.severity {
+            obj.insert("severity".into(), severity.of(&Level::WARN));
+        }
+        if let Some(version) = self.0.version {
+            obj.insert("version".into(), version.into());
+        }
+        let obj = self.0.schema.apply(obj);
+        self.write_record(&obj, "event", "logs_dropped");
+    }
+}
+
 struct Storage {
     trace_id: u128,
     span_id: u128,
     parent_id: Option<u128>,
+    sampled: bool,
     created_at: Instant,
     enter_at: Option<Instant>,
     busy_time: Duration,
     fields: BTreeMap<&'static str, serde_json::Value>,
+    fields_truncated: usize,
+}
+
+fn should_sample(trace_id: u128, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    // ids are time based, so mix the bits (splitmix64 finalizer) before comparing against the rate
+    let mut x = (trace_id as u64) ^ ((trace_id >> 64) as u64);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+    (x as f64) < rate * (u64::MAX as f64)
+}
+
+struct JsonVisitor<'a> {
+    fields: &'a mut BTreeMap<&'static str, serde_json::Value>,
+    trace_id: u128,
+    max_fields: Option<usize>,
+    integers: Integers,
+    truncated: usize,
+}
+
+impl<'a> JsonVisitor<'a> {
+    fn new(fields: &'a mut BTreeMap<&'static str, serde_json::Value>, config: &CloudNativeConfig) -> Self {
+        Self { fields, trace_id: 0, max_fields: config.max_fields, integers: config.integers, truncated: 0 }
+    }
+
+    fn insert(&mut self, name: &'static str, value: serde_json::Value) {
+        // overwriting an existing field never grows the map
+        if self.max_fields.is_some_and(|max| self.fields.len() >= max) && !self.fields.contains_key(name) {
+            self.truncated += 1;
+            return;
+        }
+        self.fields.insert(name, value);
+    }
 }
 
-struct JsonVisitor<'a>(&'a mut BTreeMap<&'static str, serde_json::Value>, u128);
-impl<'a> Visit for JsonVisitor<'a> {
+impl Visit for JsonVisitor<'_> {
     fn record_f64(&mut self, field: &Field, value: f64) {
-        self.0.insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     fn record_i64(&mut self, field: &Field, value: i64) {
-        self.0.insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), self.integers.of(value));
     }
 
     fn record_u64(&mut self, field: &Field, value: u64) {
-        self.0.insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), self.integers.of(value));
     }
 
     fn record_u128(&mut self, field: &Field, value: u128) {
         match field.name() {
             "trace_id" => {
-                self.1 = value;
+                self.trace_id = value;
             }
             _ => {
-                self.0.insert(field.name(), serde_json::Value::from(value.to_string()));
+                self.insert(field.name(), serde_json::Value::from(value.to_string()));
             }
         }
     }
 
     fn record_bool(&mut self, field: &Field, value: bool) {
-        self.0.insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     fn record_str(&mut self, field: &Field, value: &str) {
-        self.0.insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
-        self.0.insert(field.name(), serde_json::Value::from(format!("{:?}", value)));
+        self.insert(field.name(), serde_json::Value::from(format!("{:?}", value)));
     }
 }
 
@@ -244,5 +736,231 @@ fn remove_trace_id(id: &Id) {
 }
 
 pub fn get_trace_id(id: &Id) -> Option<u128> {
-    TRACE_ID_MAP.lock().unwrap().get(&id.into_u64()).map(|v| *v)
+    TRACE_ID_MAP.lock().unwrap().get(&id.into_u64()).copied()
+}
+
+/// Distinct trace ids of the spans open right now, such as in-flight requests. This is a point-in-time
+/// snapshot, the spans may close as soon as it returns.
+pub fn active_trace_ids() -> Vec<u128> {
+    let mut ids = TRACE_ID_MAP.lock().unwrap().values().copied().collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+/// Parses a trace id as logged and sent in `X-Trace-Id`, e.g. to match a response header against the logs.
+pub fn parse_trace_id(s: &str) -> Option<u128> {
+    from_radix_32(s)
+}
+
+/// Spawns `future` inside the current span, so logs from the spawned task keep the current trace id.
+pub fn spawn_with_trace<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.in_current_span())
+}
+
+/// Captures what the cloud native layer writes, to assert on the JSON records in tests.
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util {
+    use super::*;
+    use std::sync::Arc;
+
+    /// The records written by a [`capturing_layer`], clones share them.
+    #[derive(Clone, Default)]
+    pub struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        /// Every record written so far, in order.
+        pub fn records(&self) -> Vec<serde_json::Value> {
+            let buf = self.0.lock().unwrap();
+            String::from_utf8_lossy(&buf).lines()
+                .map(|line| serde_json::from_str(line).expect("record is not JSON"))
+                .collect()
+        }
+
+        /// Panics unless some record contains `expected`, objects matching when they hold at least its keys.
+        pub fn assert_record(&self, expected: serde_json::Value) {
+            let records = self.records();
+            assert!(
+                records.iter().any(|record| contains(record, &expected)),
+                "no record contains {}, records: {:#?}", expected, records,
+            );
+        }
+    }
+
+    fn contains(value: &serde_json::Value, expected: &serde_json::Value) -> bool {
+        match (value, expected) {
+            (serde_json::Value::Object(value), serde_json::Value::Object(expected)) => expected.iter()
+                .all(|(k, expected)| value.get(k).is_some_and(|value| contains(value, expected))),
+            _ => value == expected,
+        }
+    }
+
+    /// The cloud native layer with `config` writing into the returned [`Captured`], one compact record per line.
+    pub fn capturing_layer<S>(config: CloudNativeConfig) -> (impl tracing_subscriber::Layer<S>, Captured)
+    where
+        S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let config = CloudNativeConfig { pretty: false, ..config };
+        (CloudNativeLayer(config, move || writer.clone()), captured)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::capturing_layer;
+    use tracing_subscriber::{registry::LookupSpan, Registry};
+
+    #[test]
+    fn test_on_close_with_busy_time_over_elapsed() {
+        let (layer, captured) = capturing_layer(CloudNativeConfig::default());
+        let guard = tracing_subscriber::registry().with(layer).set_default();
+        let span = tracing::info_span!("overshoot");
+        span.with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>().unwrap();
+            let span = registry.span(id).unwrap();
+            span.extensions_mut().get_mut::<Storage>().unwrap().busy_time = Duration::from_secs(3600);
+        });
+        drop(span);
+        drop(guard);
+
+        captured.assert_record(json!({"name": "overshoot"}));
+    }
+
+    #[test]
+    fn test_max_fields_truncates_span_fields() {
+        let config = CloudNativeConfig { max_fields: Some(2), ..Default::default() };
+        let (layer, captured) = capturing_layer(config);
+        let guard = tracing_subscriber::registry().with(layer).set_default();
+        let span = tracing::info_span!("capped", a = 1, b = 2, c = 3, d = tracing::field::Empty);
+        span.record("a", 10);
+        span.record("d", 4);
+        drop(span);
+        drop(guard);
+
+        assert_eq!(captured.records()[0]["fields"], json!({"a": 10, "b": 2, "_fields_truncated": 2}));
+    }
+
+    #[test]
+    fn test_safe_numbers_stringifies_large_integers() {
+        let config = CloudNativeConfig { integers: Integers::SafeNumbers, ..Default::default() };
+        let (layer, captured) = capturing_layer(config);
+        let guard = tracing_subscriber::registry().with(layer).set_default();
+        tracing::info!(small = 42_u64, max_safe = (1_u64 << 53) - 1, big = u64::MAX, negative = i64::MIN, "ids");
+        drop(guard);
+
+        let record = &captured.records()[0];
+        assert_eq!(record["fields"]["small"], json!(42));
+        assert_eq!(record["fields"]["max_safe"], json!(9007199254740991_u64));
+        assert_eq!(record["fields"]["big"], json!("18446744073709551615"));
+        assert_eq!(record["fields"]["negative"], json!("-9223372036854775808"));
+    }
+
+    #[test]
+    fn test_span_start_record() {
+        let config = CloudNativeConfig { span_start: Some(Level::INFO), ..Default::default() };
+        let (layer, captured) = capturing_layer(config);
+        let guard = tracing_subscriber::registry().with(layer).set_default();
+        let span = tracing::info_span!("slow request", path = "/export");
+        let _debug = tracing::debug_span!("too verbose");
+        let records = captured.records();
+        drop(span);
+        drop(guard);
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["type"], "span_start");
+        assert_eq!(records[0]["name"], "slow request");
+        assert_eq!(records[0]["fields"], json!({"path": "/export"}));
+    }
+
+    #[test]
+    fn test_version_on_every_record() {
+        let config = CloudNativeConfig { version: Some("1.2.3"), ..Default::default() };
+        let (layer, captured) = capturing_layer(config);
+        let guard = tracing_subscriber::registry().with(layer).set_default();
+        tracing::info_span!("request").in_scope(|| tracing::info!("handled"));
+        drop(guard);
+
+        let records = captured.records();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|record| record["version"] == "1.2.3"));
+    }
+
+    #[cfg(feature = "tracing-file")]
+    #[test]
+    fn test_logs_dropped_record() {
+        let captured = test_util::Captured::default();
+        let writer = captured.clone();
+        let config = CloudNativeConfig { version: Some("1.2.3"), ..Default::default() };
+        CloudNativeLayer(config, move || writer.clone()).write_logs_dropped(5);
+
+        assert_eq!(captured.records().len(), 1);
+        captured.assert_record(json!({
+            "type": "event",
+            "level": "WARN",
+            "fields": {"logs_dropped": 5},
+            "version": "1.2.3",
+        }));
+    }
+
+    #[test]
+    fn test_util_capturing_layer() {
+        let (layer, captured) = capturing_layer(CloudNativeConfig::default());
+        let guard = tracing_subscriber::registry().with(layer).set_default();
+        tracing::info_span!("request", path = "/").in_scope(|| tracing::warn!(user = "alice", "denied"));
+        drop(guard);
+
+        assert_eq!(captured.records().len(), 2);
+        captured.assert_record(json!({"type": "event", "level": "WARN", "fields": {"user": "alice"}}));
+        captured.assert_record(json!({"type": "span", "fields": {"path": "/"}}));
+    }
+
+    #[test]
+    fn test_ecs_schema_event() {
+        let config = CloudNativeConfig { schema: Schema::Ecs { dataset: "app.log" }, ..Default::default() };
+        let (layer, captured) = capturing_layer(config);
+        let guard = tracing_subscriber::registry().with(layer).set_default();
+        tracing::info!(user = "alice", "signed in");
+        drop(guard);
+
+        let record = &captured.records()[0];
+        assert_eq!(record["message"], "signed in");
+        assert_eq!(record["log.level"], "INFO");
+        assert_eq!(record["event.dataset"], "app.log");
+        assert_eq!(record["ecs.version"], ECS_VERSION);
+        assert_eq!(record["fields"], json!({"user": "alice"}));
+        assert!(record["@timestamp"].as_str().is_some_and(|ts| ts.ends_with('Z')), "{}", record);
+        assert!(record.get("level").is_none());
+    }
+
+    #[test]
+    fn test_active_trace_ids() {
+        let subscriber = tracing_subscriber::registry()
+            .with(CloudNativeLayer(CloudNativeConfig::default(), std::io::sink));
+        let guard = subscriber.set_default();
+        let span = tracing::info_span!("active", trace_id = 42_u128);
+        let child = span.in_scope(|| tracing::info_span!("child"));
+        assert_eq!(active_trace_ids().iter().filter(|id| **id == 42).count(), 1);
+        drop(child);
+        drop(span);
+        drop(guard);
+        assert!(!active_trace_ids().contains(&42));
+    }
 }
@@ -1,15 +1,36 @@
-use serde::de::DeserializeOwned;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
 use std::env::{var, VarError};
 use std::ffi::OsStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{fs, io};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Format {
     Json,
     Yaml,
     Toml,
 }
 
+impl Format {
+    /// Guesses the format of a config document from its content. JSON is tried first, as JSON documents
+    /// are YAML too, then TOML, as a top-level `[table]` looks like a JSON array, then YAML, which only
+    /// counts when it holds a mapping or sequence since any plain text is a YAML string. An empty document
+    /// is an empty TOML table, so a config whose fields all have defaults still loads.
+    pub fn detect(buf: &str) -> Option<Format> {
+        if serde_json::from_str::<serde::de::IgnoredAny>(buf).is_ok() {
+            return Some(Format::Json);
+        }
+        if toml::from_str::<toml::Table>(buf).is_ok() {
+            return Some(Format::Toml);
+        }
+        match serde_yaml::from_str::<serde_yaml::Value>(buf) {
+            Ok(serde_yaml::Value::Mapping(_) | serde_yaml::Value::Sequence(_)) => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("unknown format")]
@@ -17,35 +38,129 @@ pub enum Error {
     #[error("unsupported format: '{0}'")]
     UnsupportedFormat(String),
 
-    #[error("deserialize json error occurred")]
+    #[error("deserialize json error occurred: {0}")]
     DeserializeJsonError(#[from] serde_json::Error),
-    #[error("deserialize yaml error occurred")]
+    #[error("deserialize yaml error occurred: {0}")]
     DeserializeYamlError(#[from]serde_yaml::Error),
-    #[error("deserialize toml error occurred")]
+    #[error("deserialize toml error occurred: {0}")]
     DeserializeTomlError(#[from] toml::de::Error),
+    /// A deserialize error below the root, `path` is the dotted path of the offending field, e.g. `db.pool[0].host`.
+    #[error("deserialize error at '{path}': {source}")]
+    DeserializeAt {
+        path: String,
+        source: Box<Error>,
+    },
+
+    #[error("serialize json error occurred: {0}")]
+    SerializeJsonError(serde_json::Error),
+    #[error("serialize yaml error occurred: {0}")]
+    SerializeYamlError(serde_yaml::Error),
+    #[error("serialize toml error occurred: {0}")]
+    SerializeTomlError(#[from] toml::ser::Error),
+
+    #[error("config include cycle at '{0}'")]
+    IncludeCycle(String),
+    #[error("config includes nested deeper than {0}")]
+    IncludeTooDeep(usize),
 
     #[error("io error occurred")]
     IoError(#[from] io::Error),
 
+    #[error("environment variable error occurred")]
+    EnvVarError(#[from] VarError),
+    #[error("environment variable '{0}' referenced by config is not set")]
+    TemplateVarNotSet(String),
+    #[error("unterminated '${{' in config template")]
+    UnterminatedTemplate,
+    /// Two variables map onto the same key, e.g. `APP_DB` as a value and `APP_DB__HOST` as a table.
+    #[error("environment variable '{0}' clashes with another variable for the same key")]
+    EnvVarClash(String),
+    #[error("deserialize environment variables error occurred: {0}")]
+    DeserializeEnvError(#[from] serde::de::value::Error),
+    #[error("dotenv error occurred")]
+    DotenvError(#[from] dotenvy::Error),
+
+    #[cfg(feature = "http-client")]
+    #[error("http error occurred: {0}")]
+    HttpError(#[from] reqwest_middleware::Error),
+
+    /// Boxed as `etcd_client::Error` is several times the size of every other variant.
     #[error("etcd client error occurred")]
-    EtcdClientError(#[from]etcd_client::Error),
+    EtcdClientError(#[source] Box<etcd_client::Error>),
     #[error("etcd key: '{0}' not exists")]
     EtcdKeyNotExists(String),
 }
 
+impl From<etcd_client::Error> for Error {
+    fn from(value: etcd_client::Error) -> Self {
+        Error::EtcdClientError(Box::new(value))
+    }
+}
+
+#[cfg(feature = "etcd-client-sync")]
+impl From<super::etcd_client_sync::Error> for Error {
+    fn from(value: super::etcd_client_sync::Error) -> Self {
+        match value {
+            super::etcd_client_sync::Error::IoError(e) => Error::IoError(e),
+            super::etcd_client_sync::Error::EtcdClientError(e) => Error::EtcdClientError(e),
+        }
+    }
+}
+
 type ConfigResult<T> = Result<T, Error>;
 
 fn deserialize<T>(format: Format, buf: &str) -> ConfigResult<T>
 where
     T: DeserializeOwned,
 {
-    Ok(match format {
-        Format::Json => serde_json::from_str(buf)?,
-        Format::Yaml => serde_yaml::from_str(buf)?,
-        Format::Toml => toml::de::from_str(buf)?,
+    match format {
+        Format::Json => {
+            let mut de = serde_json::Deserializer::from_str(buf);
+            let value = with_path(serde_path_to_error::deserialize(&mut de))?;
+            de.end()?;
+            Ok(value)
+        }
+        Format::Yaml => with_path(serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(buf))),
+        Format::Toml => with_path(serde_path_to_error::deserialize(toml::de::Deserializer::new(buf))),
+    }
+}
+
+fn with_path<T, E>(result: Result<T, serde_path_to_error::Error<E>>) -> ConfigResult<T>
+where
+    Error: From<E>,
+{
+    result.map_err(|e| {
+        let path = e.path().to_string();
+        let source = Error::from(e.into_inner());
+        if path == "." {
+            source
+        } else {
+            Error::DeserializeAt { path, source: Box::new(source) }
+        }
     })
 }
 
+/// Serializes `value` in `format`, JSON is pretty printed.
+pub fn to_string<T>(value: &T, format: Format) -> ConfigResult<String>
+where
+    T: Serialize + ?Sized,
+{
+    match format {
+        Format::Json => serde_json::to_string_pretty(value).map_err(Error::SerializeJsonError),
+        Format::Yaml => serde_yaml::to_string(value).map_err(Error::SerializeYamlError),
+        Format::Toml => Ok(toml::to_string(value)?),
+    }
+}
+
+pub fn write_file<T>(value: &T, path: impl AsRef<Path>, format: Format) -> ConfigResult<()>
+where
+    T: Serialize + ?Sized,
+{
+    fs::write(path, to_string(value, format)?)?;
+
+    Ok(())
+}
+
 pub fn from_file<T>(path: impl AsRef<Path>, format: Format) -> ConfigResult<T>
 where
     T: DeserializeOwned,
@@ -55,6 +170,49 @@ where
     deserialize(format, &buf)
 }
 
+/// Like [`from_file`], but first replaces `${VAR}` and `${VAR:-default}` in the raw text with environment
+/// values. `$${` emits a literal `${`, an unset variable without a default is an error.
+pub fn from_file_templated<T>(path: impl AsRef<Path>, format: Format) -> ConfigResult<T>
+where
+    T: DeserializeOwned,
+{
+    let buf = fs::read_to_string(path)?;
+
+    deserialize(format, &substitute_env(&buf)?)
+}
+
+fn substitute_env(buf: &str) -> ConfigResult<String> {
+    let mut out = String::with_capacity(buf.len());
+    let mut rest = buf;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').ok_or(Error::UnterminatedTemplate)?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            match (var(name), default) {
+                (Ok(value), _) => out.push_str(&value),
+                (Err(VarError::NotPresent), Some(default)) => out.push_str(default),
+                (Err(VarError::NotPresent), None) => return Err(Error::TemplateVarNotSet(name.to_string())),
+                (Err(e), _) => return Err(e.into()),
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
 pub fn from_file_auto<T>(path: impl AsRef<Path>) -> ConfigResult<T>
 where
     T: DeserializeOwned,
@@ -62,12 +220,8 @@ where
     path.as_ref().extension()
         .and_then(OsStr::to_str)
         .map(|ext| {
-            match ext {
-                "json" => Ok(Format::Json),
-                "yaml" => Ok(Format::Yaml),
-                "toml" => Ok(Format::Toml),
-                _ => Err(Error::UnsupportedFormat(ext.to_string())),
-            }
+            format_from_extension(ext)
+                .ok_or_else(|| Error::UnsupportedFormat(ext.to_string()))
         })
         .ok_or(Error::UnknownFormat)?
         .map(|format| {
@@ -75,18 +229,366 @@ where
         })?
 }
 
+/// Loads `base_path` and deep-merges `<stem>.<env_name>.<ext>` from the same directory on top of it,
+/// e.g. `config.production.yaml` over `config.yaml`. `None` takes the env name from `APP_ENV`.
+///
+/// Objects are merged key by key with the overlay winning, any other value (arrays included) is replaced
+/// as a whole. A missing overlay file, or no env name at all, loads the base file alone.
+pub fn from_file_env_overlay<T>(base_path: impl AsRef<Path>, env_name: Option<&str>) -> ConfigResult<T>
+where
+    T: DeserializeOwned,
+{
+    let base_path = base_path.as_ref();
+    let ext = base_path.extension()
+        .and_then(OsStr::to_str)
+        .ok_or(Error::UnknownFormat)?;
+    let format = || format_from_extension(ext).ok_or_else(|| Error::UnsupportedFormat(ext.to_string()));
+    let mut value: serde_json::Value = deserialize(format()?, &fs::read_to_string(base_path)?)?;
+
+    let env_name = match env_name {
+        Some(env_name) => Some(env_name.to_string()),
+        None => match var("APP_ENV") {
+            Ok(env_name) => Some(env_name),
+            Err(VarError::NotPresent) => None,
+            Err(e) => return Err(e.into()),
+        },
+    };
+    if let (Some(env_name), Some(stem)) = (env_name, base_path.file_stem().and_then(OsStr::to_str)) {
+        let overlay_path = base_path.with_file_name(format!("{}.{}.{}", stem, env_name, ext));
+        match fs::read_to_string(overlay_path) {
+            Ok(buf) => merge(&mut value, deserialize(format()?, &buf)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    with_path(serde_path_to_error::deserialize(value))
+}
+
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Loads `path` like [`from_file_auto`], replacing every string value `${include:<path>}` with the parsed
+/// content of that file, resolved against the including file's directory. The included file may itself
+/// include others, up to 8 levels deep, an include cycle is an error.
+pub fn from_file_with_includes<T>(path: impl AsRef<Path>) -> ConfigResult<T>
+where
+    T: DeserializeOwned,
+{
+    let value = load_with_includes(path.as_ref(), &mut Vec::new())?;
+
+    with_path(serde_path_to_error::deserialize(value))
+}
+
+fn load_with_includes(path: &Path, stack: &mut Vec<PathBuf>) -> ConfigResult<serde_json::Value> {
+    let path = fs::canonicalize(path)?;
+    if stack.contains(&path) {
+        return Err(Error::IncludeCycle(path.display().to_string()));
+    }
+    // the root file is not an include
+    if stack.len() > MAX_INCLUDE_DEPTH {
+        return Err(Error::IncludeTooDeep(MAX_INCLUDE_DEPTH));
+    }
+    let ext = path.extension()
+        .and_then(OsStr::to_str)
+        .ok_or(Error::UnknownFormat)?;
+    let format = format_from_extension(ext).ok_or_else(|| Error::UnsupportedFormat(ext.to_string()))?;
+    let mut value = deserialize(format, &fs::read_to_string(&path)?)?;
+
+    let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    stack.push(path);
+    resolve_includes(&mut value, &dir, stack)?;
+    stack.pop();
+    Ok(value)
+}
+
+fn resolve_includes(value: &mut serde_json::Value, dir: &Path, stack: &mut Vec<PathBuf>) -> ConfigResult<()> {
+    match value {
+        serde_json::Value::String(s) => {
+            let include = s.strip_prefix("${include:")
+                .and_then(|s| s.strip_suffix('}'))
+                .map(|include| dir.join(include.trim()));
+            if let Some(include) = include {
+                *value = load_with_includes(&include, stack)?;
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                resolve_includes(item, dir, stack)?;
+            }
+        }
+        serde_json::Value::Object(obj) => {
+            for item in obj.values_mut() {
+                resolve_includes(item, dir, stack)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn merge(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base) => merge(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn format_from_extension(ext: &str) -> Option<Format> {
+    match ext {
+        "json" => Some(Format::Json),
+        "yaml" => Some(Format::Yaml),
+        "toml" => Some(Format::Toml),
+        _ => None,
+    }
+}
+
+/// Loads a directory of files, such as a mounted Kubernetes ConfigMap, where each file is one key.
+///
+/// Files with a `json`/`yaml`/`toml` extension are parsed and keyed by their stem, any other file
+/// becomes a string value keyed by its full name. Hidden entries (like the `..data` symlink) are skipped.
+pub fn from_dir<T>(path: impl AsRef<Path>) -> ConfigResult<T>
+where
+    T: DeserializeOwned,
+{
+    let mut obj = serde_json::Map::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(OsStr::to_str) {
+            Some(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+        if !path.is_file() {
+            continue;
+        }
+
+        let buf = fs::read_to_string(&path)?;
+        let format = path.extension()
+            .and_then(OsStr::to_str)
+            .and_then(format_from_extension);
+        match (format, path.file_stem().and_then(OsStr::to_str)) {
+            (Some(format), Some(stem)) => {
+                obj.insert(stem.to_string(), deserialize::<serde_json::Value>(format, &buf)?);
+            }
+            _ => {
+                obj.insert(name.to_string(), serde_json::Value::String(buf));
+            }
+        }
+    }
+
+    with_path(serde_path_to_error::deserialize(serde_json::Value::Object(obj)))
+}
+
+/// Loads `T` from the environment variables starting with `prefix`, the rest of each name is lowercased
+/// and split on `separator` into nested keys, so with prefix `APP_` and separator `__`,
+/// `APP_DB__HOST=x` becomes `{ db: { host: "x" } }`.
+///
+/// Values are parsed into the type of their field, sequences are comma separated. Variables mapping onto
+/// the same key, e.g. `APP_DB` next to `APP_DB__HOST`, are an [`Error::EnvVarClash`].
+pub fn from_env_nested<T>(prefix: &str, separator: &str) -> ConfigResult<T>
+where
+    T: DeserializeOwned,
+{
+    let mut root = env_nested::Node::Map(Default::default());
+    for (name, value) in std::env::vars() {
+        if let Some(name) = name.strip_prefix(prefix) {
+            let path = name.to_lowercase();
+            root.insert(&path.split(separator).collect::<Vec<_>>(), value)
+                .map_err(|()| Error::EnvVarClash(format!("{}{}", prefix, name)))?;
+        }
+    }
+    with_path(serde_path_to_error::deserialize(root))
+}
+
+mod env_nested {
+    use serde::de::{self, value::{Error, MapDeserializer, SeqDeserializer}, IntoDeserializer, Visitor};
+    use std::collections::{btree_map::Entry, BTreeMap};
+
+    pub(super) enum Node {
+        Leaf(String),
+        Map(BTreeMap<String, Node>),
+    }
+
+    impl Node {
+        // fails if a value is already at `path` or on the way to it, or a table is at `path`
+        pub(super) fn insert(&mut self, path: &[&str], value: String) -> Result<(), ()> {
+            let Node::Map(map) = self else { return Err(()) };
+            match path {
+                [] => Err(()),
+                [key] => match map.entry(key.to_string()) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(Node::Leaf(value));
+                        Ok(())
+                    }
+                    Entry::Occupied(_) => Err(()),
+                },
+                [key, path @ ..] => {
+                    map.entry(key.to_string())
+                        .or_insert_with(|| Node::Map(BTreeMap::new()))
+                        .insert(path, value)
+                }
+            }
+        }
+    }
+
+    impl<'de> IntoDeserializer<'de, Error> for Node {
+        type Deserializer = Self;
+
+        fn into_deserializer(self) -> Self {
+            self
+        }
+    }
+
+    macro_rules! parse_leaf {
+        ($($method:ident => $visit:ident,)*) => {
+            $(
+                fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                    match self {
+                        Node::Leaf(s) => visitor.$visit(s.parse().map_err(de::Error::custom)?),
+                        node => node.deserialize_any(visitor),
+                    }
+                }
+            )*
+        };
+    }
+
+    impl<'de> de::Deserializer<'de> for Node {
+        type Error = Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            match self {
+                Node::Leaf(s) => visitor.visit_string(s),
+                Node::Map(map) => visitor.visit_map(MapDeserializer::new(map.into_iter())),
+            }
+        }
+
+        parse_leaf! {
+            deserialize_bool => visit_bool,
+            deserialize_i8 => visit_i8,
+            deserialize_i16 => visit_i16,
+            deserialize_i32 => visit_i32,
+            deserialize_i64 => visit_i64,
+            deserialize_u8 => visit_u8,
+            deserialize_u16 => visit_u16,
+            deserialize_u32 => visit_u32,
+            deserialize_u64 => visit_u64,
+            deserialize_f32 => visit_f32,
+            deserialize_f64 => visit_f64,
+        }
+
+        fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            visitor.visit_some(self)
+        }
+
+        fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            match self {
+                Node::Leaf(s) => {
+                    let items = s.split(',')
+                        .filter(|item| !item.is_empty())
+                        .map(|item| Node::Leaf(item.trim().to_string()))
+                        .collect::<Vec<_>>();
+                    visitor.visit_seq(SeqDeserializer::new(items.into_iter()))
+                }
+                node => node.deserialize_any(visitor),
+            }
+        }
+
+        fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, Error> {
+            visitor.visit_newtype_struct(self)
+        }
+
+        fn deserialize_enum<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            _: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Error> {
+            match self {
+                Node::Leaf(s) => visitor.visit_enum(s.into_deserializer()),
+                node => node.deserialize_any(visitor),
+            }
+        }
+
+        serde::forward_to_deserialize_any! {
+            char str string bytes byte_buf unit unit_struct tuple
+            tuple_struct map struct identifier ignored_any i128 u128
+        }
+    }
+}
+
+/// Fetches config over HTTP(S) with `client`, normally the tracing one from [`crate::http::client`].
+/// Without a format it is picked from the `Content-Type`, then from the URL's extension, then by
+/// [`Format::detect`] on the body.
+#[cfg(feature = "http-client")]
+pub async fn from_url<T>(client: &reqwest_middleware::ClientWithMiddleware, url: &str, format: Option<Format>) -> ConfigResult<T>
+where
+    T: DeserializeOwned,
+{
+    let resp = client.get(url)
+        .send()
+        .await?
+        .error_for_status()
+        .map_err(reqwest_middleware::Error::from)?;
+    let format = format.or_else(|| resp.headers().get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(format_from_content_type)
+        .or_else(|| Path::new(resp.url().path()).extension()
+            .and_then(OsStr::to_str)
+            .and_then(format_from_extension)));
+    let buf = resp.text().await.map_err(reqwest_middleware::Error::from)?;
+    let format = match format {
+        Some(format) => format,
+        None => Format::detect(&buf).ok_or(Error::UnknownFormat)?,
+    };
+
+    deserialize(format, &buf)
+}
+
+#[cfg(feature = "http-client")]
+fn format_from_content_type(content_type: &str) -> Option<Format> {
+    let mime = content_type.split(';').next()?.trim();
+    match mime {
+        "application/json" => Some(Format::Json),
+        "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml" => Some(Format::Yaml),
+        "application/toml" | "text/toml" => Some(Format::Toml),
+        _ => None,
+    }
+}
+
 pub async fn from_etcd<T>(client: &mut etcd_client::Client, key: &str, format: Format) -> ConfigResult<T>
 where
     T: DeserializeOwned,
 {
     let resp = client.get(key, None).await?;
-    let kvs = resp.kvs();
-    if kvs.len() == 0 {
-        return Err(Error::EtcdKeyNotExists(key.to_string()));
-    }
+    deserialize_etcd_value(key, &resp, Some(format))
+}
 
-    let buf = kvs[0].value_str()?;
-    deserialize(format, buf)
+/// Like [`from_etcd`] for keys whose format isn't known up front, detecting it from the value with
+/// [`Format::detect`]. Fails with [`Error::UnknownFormat`] when the value matches no format.
+pub async fn from_etcd_auto<T>(client: &mut etcd_client::Client, key: &str) -> ConfigResult<T>
+where
+    T: DeserializeOwned,
+{
+    let resp = client.get(key, None).await?;
+    deserialize_etcd_value(key, &resp, None)
+}
+
+/// Reads all `keys` in one transaction, so one round-trip, each deserialized independently so one missing or
+/// malformed key doesn't fail the others.
+pub async fn from_etcd_many<T>(client: &mut etcd_client::Client, keys: &[&str], format: Format) -> ConfigResult<HashMap<String, ConfigResult<T>>>
+where
+    T: DeserializeOwned,
+{
+    let resp = client.txn(get_many_txn(keys)).await?;
+    Ok(deserialize_etcd_values(keys, &resp, format))
 }
 
 #[cfg(feature = "etcd-client-sync")]
@@ -95,15 +597,98 @@ where
     T: DeserializeOwned,
 {
     let resp = client.get(key, None)?;
+    deserialize_etcd_value(key, &resp, Some(format))
+}
+
+#[cfg(feature = "etcd-client-sync")]
+pub fn from_etcd_auto_sync<T>(client: &mut super::etcd_client_sync::Client, key: &str) -> ConfigResult<T>
+where
+    T: DeserializeOwned,
+{
+    let resp = client.get(key, None)?;
+    deserialize_etcd_value(key, &resp, None)
+}
+
+#[cfg(feature = "etcd-client-sync")]
+pub fn from_etcd_many_sync<T>(client: &mut super::etcd_client_sync::Client, keys: &[&str], format: Format) -> ConfigResult<HashMap<String, ConfigResult<T>>>
+where
+    T: DeserializeOwned,
+{
+    let resp = client.txn(get_many_txn(keys))?;
+    Ok(deserialize_etcd_values(keys, &resp, format))
+}
+
+/// `None` detects the format from the value.
+fn deserialize_etcd_value<T>(key: &str, resp: &etcd_client::GetResponse, format: Option<Format>) -> ConfigResult<T>
+where
+    T: DeserializeOwned,
+{
     let kvs = resp.kvs();
-    if kvs.len() == 0 {
+    if kvs.is_empty() {
         return Err(Error::EtcdKeyNotExists(key.to_string()));
     }
 
     let buf = kvs[0].value_str()?;
+    let format = match format {
+        Some(format) => format,
+        None => Format::detect(buf).ok_or(Error::UnknownFormat)?,
+    };
     deserialize(format, buf)
 }
 
+fn get_many_txn(keys: &[&str]) -> etcd_client::Txn {
+    use etcd_client::{Txn, TxnOp};
+
+    Txn::new().and_then(keys.iter().map(|key| TxnOp::get(*key, None)).collect::<Vec<_>>())
+}
+
+fn deserialize_etcd_values<T>(keys: &[&str], resp: &etcd_client::TxnResponse, format: Format) -> HashMap<String, ConfigResult<T>>
+where
+    T: DeserializeOwned,
+{
+    // the responses of a txn come back in the order of its operations
+    keys.iter()
+        .zip(resp.op_responses())
+        .map(|(key, op)| {
+            let value = match op {
+                etcd_client::TxnOpResponse::Get(resp) => deserialize_etcd_value(key, &resp, Some(format)),
+                _ => unreachable!("txn only holds get operations"),
+            };
+            (key.to_string(), value)
+        })
+        .collect()
+}
+
+/// Connects to `endpoint`, reads one key and disconnects, for short-lived programs without a client at hand.
+#[cfg(feature = "etcd-client-sync")]
+pub fn from_etcd_connect_sync<T>(endpoint: &str, key: &str, format: Format) -> ConfigResult<T>
+where
+    T: DeserializeOwned,
+{
+    let mut client = super::etcd_client_sync::Client::connect([endpoint], None)?;
+    from_etcd_sync(&mut client, key, format)
+}
+
+fn put_if_unchanged_txn(key: &str, expected_value: &str, new_value: &str) -> etcd_client::Txn {
+    use etcd_client::{Compare, CompareOp, Txn, TxnOp};
+
+    Txn::new()
+        .when([Compare::value(key, CompareOp::Equal, expected_value)])
+        .and_then([TxnOp::put(key, new_value, None)])
+}
+
+/// Writes `new_value` only if the key still holds `expected_value`, returning whether the write happened.
+pub async fn put_if_unchanged(client: &mut etcd_client::Client, key: &str, expected_value: &str, new_value: &str) -> ConfigResult<bool> {
+    let resp = client.txn(put_if_unchanged_txn(key, expected_value, new_value)).await?;
+    Ok(resp.succeeded())
+}
+
+#[cfg(feature = "etcd-client-sync")]
+pub fn put_if_unchanged_sync(client: &mut super::etcd_client_sync::Client, key: &str, expected_value: &str, new_value: &str) -> ConfigResult<bool> {
+    let resp = client.txn(put_if_unchanged_txn(key, expected_value, new_value))?;
+    Ok(resp.succeeded())
+}
+
 pub struct EtcdConfig {
     endpoint: String,
     enable_auth: bool,
@@ -130,6 +715,14 @@ impl EtcdConfig {
         })
     }
 
+    /// Loads the `.env` file at `path` into the environment, then reads the config as [`Self::from_env`].
+    ///
+    /// Variables already set in the process environment take precedence over the file.
+    pub fn from_dotenv(path: impl AsRef<Path>) -> ConfigResult<Self> {
+        dotenvy::from_path(path)?;
+        Ok(Self::from_env()?)
+    }
+
     pub fn endpoint(&self) -> &String {
         &self.endpoint
     }
@@ -148,7 +741,7 @@ impl EtcdConfig {
 
     pub async fn connect(&self) -> Result<etcd_client::Client, etcd_client::Error> {
         etcd_client::Client::connect(
-            &[&self.endpoint],
+            [&self.endpoint],
             self.enable_auth
                 .then_some(etcd_client::ConnectOptions::new()
                     .with_user(self.user.as_ref().unwrap(), self.password.as_ref().unwrap())),
@@ -158,10 +751,206 @@ impl EtcdConfig {
     #[cfg(feature = "etcd-client-sync")]
     pub fn connect_sync(&self) -> Result<super::etcd_client_sync::Client, super::etcd_client_sync::Error> {
         super::etcd_client_sync::Client::connect(
-            &[&self.endpoint],
+            [&self.endpoint],
             self.enable_auth
                 .then_some(etcd_client::ConnectOptions::new()
                     .with_user(self.user.as_ref().unwrap(), self.password.as_ref().unwrap())),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // a fresh directory per test, removed on drop
+    struct TempDir(PathBuf);
+    impl TempDir {
+        fn new() -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "myrust-config-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed),
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn write(&self, name: &str, content: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, content).unwrap();
+            path
+        }
+    }
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // `0.json` includes `1.json` and so on, `levels` includes deep
+    fn include_chain(dir: &TempDir, levels: usize) -> PathBuf {
+        for i in 0..levels {
+            dir.write(&format!("{}.json", i), &format!(r#"{{"next": "${{include:{}.json}}"}}"#, i + 1));
+        }
+        dir.write(&format!("{}.json", levels), r#"{"leaf": true}"#);
+        dir.0.join("0.json")
+    }
+
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    struct EnvConfig {
+        name: String,
+        debug: bool,
+        db: DbConfig,
+        #[serde(default)]
+        tags: Vec<String>,
+    }
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    struct DbConfig {
+        host: String,
+        port: u16,
+        timeout: Option<f64>,
+    }
+
+    // env vars are process wide, so each test sets and reads its own prefix
+    fn set_vars(vars: &[(&str, &str)]) {
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+    }
+
+    #[test]
+    fn test_env_nested() {
+        set_vars(&[
+            ("MYRUST_NESTED_NAME", "app"),
+            ("MYRUST_NESTED_DEBUG", "true"),
+            ("MYRUST_NESTED_DB__HOST", "localhost"),
+            ("MYRUST_NESTED_DB__PORT", "5432"),
+            ("MYRUST_NESTED_DB__TIMEOUT", "1.5"),
+            ("MYRUST_NESTED_TAGS", "a, b,c"),
+        ]);
+
+        let config: EnvConfig = from_env_nested("MYRUST_NESTED_", "__").unwrap();
+        assert_eq!(config, EnvConfig {
+            name: "app".to_string(),
+            debug: true,
+            db: DbConfig { host: "localhost".to_string(), port: 5432, timeout: Some(1.5) },
+            tags: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+        });
+    }
+
+    #[test]
+    fn test_env_nested_unparsable_values() {
+        set_vars(&[
+            ("MYRUST_BAD_NAME", "app"),
+            ("MYRUST_BAD_DEBUG", "yes"),
+            ("MYRUST_BAD_DB__HOST", "localhost"),
+            ("MYRUST_BAD_DB__PORT", "5432"),
+        ]);
+        assert!(from_env_nested::<EnvConfig>("MYRUST_BAD_", "__").is_err());
+
+        std::env::set_var("MYRUST_BAD_DEBUG", "false");
+        std::env::set_var("MYRUST_BAD_DB__PORT", "99999");
+        assert!(from_env_nested::<EnvConfig>("MYRUST_BAD_", "__").is_err());
+    }
+
+    #[test]
+    fn test_env_nested_clash() {
+        set_vars(&[("MYRUST_CLASH_DB", "x"), ("MYRUST_CLASH_DB__HOST", "y")]);
+
+        assert!(matches!(
+            from_env_nested::<serde_json::Value>("MYRUST_CLASH_", "__"),
+            Err(Error::EnvVarClash(name)) if name.starts_with("MYRUST_CLASH_DB"),
+        ));
+    }
+
+    #[test]
+    fn test_env_nested_no_match() {
+        #[derive(serde::Deserialize)]
+        struct Optional {
+            name: Option<String>,
+        }
+
+        let config: Optional = from_env_nested("MYRUST_UNSET_PREFIX_", "__").unwrap();
+        assert!(config.name.is_none());
+        assert!(from_env_nested::<EnvConfig>("MYRUST_UNSET_PREFIX_", "__").is_err());
+    }
+
+    #[test]
+    fn test_substitute_env() {
+        std::env::set_var("MYRUST_SUBST_HOST", "db.internal");
+
+        assert_eq!(substitute_env("host = \"${MYRUST_SUBST_HOST}\"").unwrap(), "host = \"db.internal\"");
+        assert_eq!(substitute_env("${MYRUST_SUBST_HOST:-fallback}").unwrap(), "db.internal");
+        assert_eq!(substitute_env("port = ${MYRUST_SUBST_UNSET:-5432}").unwrap(), "port = 5432");
+        assert_eq!(substitute_env("${MYRUST_SUBST_UNSET:-}").unwrap(), "");
+        assert_eq!(substitute_env("price = $5, literal $${MYRUST_SUBST_HOST}").unwrap(), "price = $5, literal ${MYRUST_SUBST_HOST}");
+    }
+
+    #[test]
+    fn test_substitute_env_errors() {
+        assert!(matches!(
+            substitute_env("host = ${MYRUST_SUBST_MISSING}"),
+            Err(Error::TemplateVarNotSet(name)) if name == "MYRUST_SUBST_MISSING",
+        ));
+        assert!(matches!(substitute_env("host = ${MYRUST_SUBST_HOST"), Err(Error::UnterminatedTemplate)));
+    }
+
+    #[test]
+    fn test_include_depth_limit() {
+        let dir = TempDir::new();
+        let root = include_chain(&dir, MAX_INCLUDE_DEPTH);
+        assert!(from_file_with_includes::<serde_json::Value>(&root).is_ok());
+
+        let dir = TempDir::new();
+        let root = include_chain(&dir, MAX_INCLUDE_DEPTH + 1);
+        assert!(matches!(
+            from_file_with_includes::<serde_json::Value>(&root),
+            Err(Error::IncludeTooDeep(MAX_INCLUDE_DEPTH))
+        ));
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = TempDir::new();
+        let root = dir.write("a.json", r#"{"b": "${include:b.json}"}"#);
+        dir.write("b.json", r#"{"a": "${include:a.json}"}"#);
+
+        assert!(matches!(from_file_with_includes::<serde_json::Value>(&root), Err(Error::IncludeCycle(_))));
+    }
+
+    #[test]
+    fn test_include_relative_to_including_file() {
+        let dir = TempDir::new();
+        let root = dir.write("app.json", r#"{"db": "${include:conf/db.json}"}"#);
+        dir.write("conf/db.json", r#"{"host": "localhost", "pool": "${include:pool.toml}"}"#);
+        dir.write("conf/pool.toml", "size = 4");
+
+        let value: serde_json::Value = from_file_with_includes(&root).unwrap();
+        assert_eq!(value, serde_json::json!({"db": {"host": "localhost", "pool": {"size": 4}}}));
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(Format::detect(r#"{"name": "app", "port": 8080}"#), Some(Format::Json));
+        assert_eq!(Format::detect(r#"[1, 2, 3]"#), Some(Format::Json));
+        assert_eq!(Format::detect("[db]\nhost = \"localhost\"\nport = 5432\n"), Some(Format::Toml));
+        assert_eq!(Format::detect("name: app\ndb:\n  port: 5432\n"), Some(Format::Yaml));
+        assert_eq!(Format::detect("- a\n- b\n"), Some(Format::Yaml));
+        assert_eq!(Format::detect("just some text"), None);
+        assert_eq!(Format::detect(""), Some(Format::Toml));
+    }
+
+    #[cfg(feature = "http-client")]
+    #[test]
+    fn test_format_from_content_type() {
+        assert_eq!(format_from_content_type("application/json"), Some(Format::Json));
+        assert_eq!(format_from_content_type("application/json; charset=utf-8"), Some(Format::Json));
+        assert_eq!(format_from_content_type("application/x-yaml"), Some(Format::Yaml));
+        assert_eq!(format_from_content_type("text/yaml"), Some(Format::Yaml));
+        assert_eq!(format_from_content_type("application/toml"), Some(Format::Toml));
+        assert_eq!(format_from_content_type("text/plain"), None);
+        assert_eq!(format_from_content_type(""), None);
+    }
+}
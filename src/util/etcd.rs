@@ -0,0 +1,188 @@
+use super::config::Error;
+use etcd_client::{Client, Compare, CompareOp, PutOptions, Txn, TxnOp};
+use std::{future::Future, time::Duration};
+use tokio::{
+    runtime::Handle,
+    sync::watch,
+    task::JoinHandle,
+    time::{sleep, timeout_at, Instant},
+};
+
+type EtcdResult<T> = Result<T, Error>;
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+
+struct KeptLease {
+    client: Client,
+    id: i64,
+    held: watch::Receiver<bool>,
+    keep_alive: JoinHandle<()>,
+}
+
+impl KeptLease {
+    async fn grant(client: &mut Client, ttl: i64) -> EtcdResult<Self> {
+        let id = client.lease_grant(ttl, None).await?.id();
+        let (held_tx, held) = watch::channel(true);
+        let keep_alive = tokio::spawn(keep_alive(client.clone(), id, ttl, held_tx));
+        Ok(Self {
+            client: client.clone(),
+            id,
+            held,
+            keep_alive,
+        })
+    }
+
+    fn is_held(&self) -> bool {
+        *self.held.borrow()
+    }
+
+    fn lost(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut held = self.held.clone();
+        async move {
+            let _ = held.wait_for(|held| !held).await;
+        }
+    }
+}
+
+impl Drop for KeptLease {
+    fn drop(&mut self) {
+        self.keep_alive.abort();
+        // without a runtime to revoke on, the lease is left to expire after its ttl
+        if let Ok(handle) = Handle::try_current() {
+            let mut client = self.client.clone();
+            let id = self.id;
+            handle.spawn(async move {
+                let _ = client.lease_revoke(id).await;
+            });
+        }
+    }
+}
+
+/// Renews the lease every third of its ttl, reconnecting with backoff after errors.
+///
+/// Gives up and flips `held` to false only once the lease has gone a full ttl without a
+/// successful renewal, or etcd reports that it no longer knows the lease.
+async fn keep_alive(mut client: Client, id: i64, ttl: i64, held: watch::Sender<bool>) {
+    let ttl = Duration::from_secs(ttl.max(1) as u64);
+    let period = ttl / 3;
+    // counted from before the renewal was sent, so it never outlives etcd's own deadline
+    let mut expires = Instant::now() + ttl;
+    let mut backoff = RETRY_BASE_DELAY;
+    while Instant::now() < expires {
+        if let Ok(Ok((mut keeper, mut stream))) = timeout_at(expires, client.lease_keep_alive(id)).await {
+            loop {
+                let sent = Instant::now();
+                let renewed = timeout_at(expires, async {
+                    keeper.keep_alive().await?;
+                    stream.message().await
+                })
+                .await;
+                match renewed {
+                    Ok(Ok(Some(resp))) if resp.ttl() > 0 => {
+                        expires = sent + Duration::from_secs(resp.ttl() as u64);
+                        backoff = RETRY_BASE_DELAY;
+                    }
+                    // a zero ttl means the lease has already expired or been revoked
+                    Ok(Ok(Some(_))) => {
+                        held.send_replace(false);
+                        return;
+                    }
+                    _ => break,
+                }
+                sleep(period).await;
+            }
+        }
+        sleep(backoff.min(expires.saturating_duration_since(Instant::now()))).await;
+        backoff = (backoff * 2).min(period);
+    }
+    held.send_replace(false);
+}
+
+/// An instance registered under `/services/<name>/<id>`, the key is removed when this is dropped.
+pub struct ServiceRegistration {
+    key: String,
+    lease: KeptLease,
+}
+
+impl ServiceRegistration {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn lease_id(&self) -> i64 {
+        self.lease.id
+    }
+
+    /// Whether the lease is still being kept alive, false once it has expired and the key is gone.
+    pub fn is_held(&self) -> bool {
+        self.lease.is_held()
+    }
+
+    /// Resolves once the registration is lost, after which the instance has to register again to be discoverable.
+    pub fn lost(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.lease.lost()
+    }
+}
+
+/// Registers `addr` under `/services/<name>/<id>` with a lease of `ttl` seconds, using the lease id as the instance id.
+///
+/// The lease is kept alive by a spawned task until the returned registration is dropped,
+/// if the process dies the key expires after `ttl`. Failed renewals are retried, a lease that
+/// still expires is reported by [`ServiceRegistration::is_held`] and [`ServiceRegistration::lost`].
+pub async fn register_service(client: &mut Client, name: &str, addr: &str, ttl: i64) -> EtcdResult<ServiceRegistration> {
+    let lease = KeptLease::grant(client, ttl).await?;
+    let key = format!("/services/{}/{:x}", name, lease.id);
+    client.put(key.as_str(), addr, Some(PutOptions::new().with_lease(lease.id))).await?;
+
+    Ok(ServiceRegistration {
+        key,
+        lease,
+    })
+}
+
+/// A held lock, released by revoking its lease when dropped.
+pub struct LockGuard {
+    key: String,
+    lease: KeptLease,
+}
+
+impl LockGuard {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn lease_id(&self) -> i64 {
+        self.lease.id
+    }
+
+    /// Whether the lease is still being kept alive, false once it has expired and the lock may have a new holder.
+    pub fn is_held(&self) -> bool {
+        self.lease.is_held()
+    }
+
+    /// Resolves once the lock is lost, for leader-only work to `select!` against.
+    pub fn lost(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.lease.lost()
+    }
+}
+
+/// Acquires `key` only if it does not exist, returning `None` when another holder has it.
+///
+/// The key is written with a lease of `ttl` seconds that is kept alive while the guard is held.
+/// At most one holder exists at a time as long as leases are honoured: failed renewals are retried,
+/// but a holder that loses contact with etcd for longer than `ttl` loses the lock, which
+/// [`LockGuard::is_held`] and [`LockGuard::lost`] report.
+pub async fn try_lock(client: &mut Client, key: &str, ttl: i64) -> EtcdResult<Option<LockGuard>> {
+    let lease = KeptLease::grant(client, ttl).await?;
+    let txn = Txn::new()
+        .when([Compare::create_revision(key, CompareOp::Equal, 0)])
+        .and_then([TxnOp::put(key, lease.id.to_string(), Some(PutOptions::new().with_lease(lease.id)))]);
+    if !client.txn(txn).await?.succeeded() {
+        return Ok(None);
+    }
+
+    Ok(Some(LockGuard {
+        key: key.to_string(),
+        lease,
+    }))
+}
@@ -1,4 +1,4 @@
-use etcd_client::{GetOptions, GetResponse};
+use etcd_client::{GetOptions, GetResponse, Txn, TxnResponse};
 use std::sync::Arc;
 
 #[derive(thiserror::Error, Debug)]
@@ -6,7 +6,13 @@ pub enum Error {
     #[error("io error occurred")]
     IoError(#[from]std::io::Error),
     #[error("etcd client error occurred")]
-    EtcdClientError(#[from]etcd_client::Error),
+    EtcdClientError(#[source] Box<etcd_client::Error>),
+}
+
+impl From<etcd_client::Error> for Error {
+    fn from(value: etcd_client::Error) -> Self {
+        Error::EtcdClientError(Box::new(value))
+    }
 }
 
 #[derive(Clone)]
@@ -15,7 +21,7 @@ pub struct Client {
     rt: Arc<tokio::runtime::Runtime>,
 }
 
-type EtcdResult<T> = Result<T, etcd_client::Error>;
+type EtcdResult<T> = Result<T, Error>;
 
 impl Client {
     pub fn new(inner: etcd_client::Client) -> Result<Self, Error> {
@@ -46,6 +52,10 @@ impl Client {
     }
 
     pub fn get(&mut self, key: impl Into<Vec<u8>>, options: Option<GetOptions>) -> EtcdResult<GetResponse> {
-        self.rt.block_on(self.inner.get(key, options))
+        Ok(self.rt.block_on(self.inner.get(key, options))?)
+    }
+
+    pub fn txn(&mut self, txn: Txn) -> EtcdResult<TxnResponse> {
+        Ok(self.rt.block_on(self.inner.txn(txn))?)
     }
 }
\ No newline at end of file
@@ -1,67 +1,221 @@
-use mac_address::MacAddress;
+use std::hash::{DefaultHasher, Hash as _, Hasher as _};
 use std::sync::{LazyLock, Mutex};
 use std::time::SystemTime;
 
 const MAC_ADDRESS_BITS: usize = 48;
 const COUNTER_BITS: usize = 64 - MAC_ADDRESS_BITS;
+const CHECKSUM_BITS: usize = 10;
 
-static GENERATOR: LazyLock<Mutex<Generator>> = LazyLock::new(|| { Mutex::new(Generator::new()) });
-struct Generator {
+trait Clock {
+    fn now_nanos(&self) -> u128;
+}
+
+struct SystemClock;
+impl Clock for SystemClock {
+    fn now_nanos(&self) -> u128 {
+        SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos()
+    }
+}
+
+static GENERATOR: LazyLock<Mutex<Generator<SystemClock>>> = LazyLock::new(|| { Mutex::new(Generator::new(SystemClock)) });
+struct Generator<C> {
+    clock: C,
     mac: u64,
     counter: u64,
     last_time: u128,
 }
-impl Generator {
-    fn new() -> Self {
-        let mac_addr: MacAddress;
-        match mac_address::get_mac_address() {
-            Ok(Some(m)) => { mac_addr = m; }
-            Ok(None) => {
-                panic!("no mac address");
-            }
-            Err(e) => {
-                panic!("get mac address error: {:?}", e);
-            }
-        }
-
-        let mut mac: u64 = 0;
-        for b in mac_addr.bytes() {
-            mac = (mac << 8) | (b as u64);
-        }
+impl<C: Clock> Generator<C> {
+    fn new(clock: C) -> Self {
+        Self::with_mac(clock, node_id(mac_node_id, random_node_id))
+    }
 
+    fn with_mac(clock: C, mac: u64) -> Self {
         Self {
+            clock,
             mac,
             counter: 0,
             last_time: 0,
         }
     }
+
+    fn next(&mut self) -> u128 {
+        let now = self.clock.now_nanos();
+        if self.last_time < now {
+            self.last_time = now;
+            self.counter = 0;
+        } else {
+            self.counter += 1;
+            // the counter ran out within one nanosecond, borrow the next one rather than repeat an id
+            if self.counter == 1 << COUNTER_BITS {
+                self.last_time += 1;
+                self.counter = 0;
+            }
+        }
+        (self.last_time << 64) | ((self.counter as u128) << MAC_ADDRESS_BITS) | (self.mac as u128)
+    }
+}
+
+/// The 48 bit node id telling hosts apart, from the first source available: the mac address, random bits
+/// from the OS, or a hash of the hostname and pid. Only the mac address is unique by construction, random
+/// ids collide rarely, and hashed ones are best-effort, as sandboxes often share hostnames and pids.
+fn node_id(mac: impl FnOnce() -> Option<u64>, random: impl FnOnce() -> Option<u64>) -> u64 {
+    if let Some(mac) = mac() {
+        return mac;
+    }
+    if let Some(random) = random() {
+        return random & ((1 << MAC_ADDRESS_BITS) - 1);
+    }
+    // the tracing layers generate ids, so logging through tracing here could re-enter them
+    eprintln!("id_gen: no mac address or OS randomness, node id hashed from hostname and pid, ids may collide across hosts");
+    host_node_id()
+}
+
+fn mac_node_id() -> Option<u64> {
+    let mac = mac_address::get_mac_address().ok()??;
+    Some(mac.bytes().iter().fold(0, |mac, &b| (mac << 8) | b as u64))
+}
+
+fn random_node_id() -> Option<u64> {
+    use std::io::Read as _;
+
+    let mut buf = [0; 8];
+    std::fs::File::open("/dev/urandom").ok()?.read_exact(&mut buf).ok()?;
+    Some(u64::from_le_bytes(buf))
+}
+
+fn host_node_id() -> u64 {
+    let hostname = std::env::var("HOSTNAME").ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .unwrap_or_default();
+    let mut hasher = DefaultHasher::new();
+    hostname.trim().hash(&mut hasher);
+    std::process::id().hash(&mut hasher);
+    hasher.finish() & ((1 << MAC_ADDRESS_BITS) - 1)
 }
 
 pub fn gen_id() -> u128 {
-    let mut generator = GENERATOR.lock().unwrap();
-    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
-    if generator.last_time < now {
-        generator.last_time = now;
-        generator.counter = 0;
-    } else {
-        generator.counter = (generator.counter + 1) % (1 << COUNTER_BITS);
-    }
-    (now << 64) | ((generator.counter as u128) << MAC_ADDRESS_BITS) | (generator.mac as u128)
+    GENERATOR.lock().unwrap().next()
+}
+
+/// Like [`gen_id`] but the lowest two radix 32 digits hold a checksum of the others, so [`verify_id`]
+/// rejects ids with a mistyped digit or two swapped digits.
+///
+/// The checksum replaces the top 10 bits of the mac address, so hosts whose mac addresses only differ
+/// in those bits can generate the same id.
+pub fn gen_id_checked() -> u128 {
+    let id = gen_id();
+    let node_mask = (1u128 << (MAC_ADDRESS_BITS - CHECKSUM_BITS)) - 1;
+    let data = (id >> MAC_ADDRESS_BITS << (MAC_ADDRESS_BITS - CHECKSUM_BITS)) | (id & node_mask);
+    (data << CHECKSUM_BITS) | checksum(data)
+}
+
+/// Checks an id generated by [`gen_id_checked`].
+pub fn verify_id(id: u128) -> bool {
+    checksum(id >> CHECKSUM_BITS) == id & ((1 << CHECKSUM_BITS) - 1)
+}
+
+// digits weighted by position modulo a prime, the product of a weight difference and a digit
+// difference can't be a multiple of it, so any substitution or transposition changes the sum
+fn checksum(data: u128) -> u128 {
+    const PRIME: u128 = 1021;
+    let mut sum = 0;
+    let mut data = data;
+    let mut weight = 1;
+    while data != 0 {
+        sum += weight * (data & 0x1f);
+        data >>= 5;
+        weight += 1;
+    }
+    sum % PRIME
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::Cell;
     use std::collections::HashSet;
+    use std::rc::Rc;
     use std::sync::{Arc, Barrier};
     use std::thread;
 
+    struct ManualClock(Rc<Cell<u128>>);
+    impl Clock for ManualClock {
+        fn now_nanos(&self) -> u128 {
+            self.0.get()
+        }
+    }
+
+    fn manual_generator(now: u128) -> (Generator<ManualClock>, Rc<Cell<u128>>) {
+        let time = Rc::new(Cell::new(now));
+        (Generator::with_mac(ManualClock(time.clone()), 0xabcdef), time)
+    }
+
+    #[test]
+    fn test_counter_within_same_nanosecond() {
+        let (mut generator, time) = manual_generator(1);
+        assert_eq!(generator.next(), (1 << 64) | 0xabcdef);
+        assert_eq!(generator.next(), (1 << 64) | (1 << MAC_ADDRESS_BITS) | 0xabcdef);
+        time.set(2);
+        assert_eq!(generator.next(), (2 << 64) | 0xabcdef);
+    }
+
+    #[test]
+    fn test_counter_overflow_advances_time() {
+        let (mut generator, _) = manual_generator(1);
+        let mut ids = HashSet::new();
+        for _ in 0..(1 << COUNTER_BITS) + 2 {
+            assert!(ids.insert(generator.next()));
+        }
+        assert_eq!(generator.next(), (2 << 64) | (2 << MAC_ADDRESS_BITS) | 0xabcdef);
+    }
+
+    #[test]
+    fn test_clock_going_backwards_keeps_ids_unique() {
+        let (mut generator, time) = manual_generator(10);
+        let first = generator.next();
+        time.set(5);
+        assert_eq!(generator.next(), first | (1 << MAC_ADDRESS_BITS));
+    }
+
+    #[test]
+    fn test_gen_id_tight_loop_unique() {
+        let mut ids = HashSet::new();
+        for _ in 0..200_000 {
+            assert!(ids.insert(gen_id()));
+        }
+    }
+
+    #[test]
+    fn test_node_id_fallbacks() {
+        assert_eq!(node_id(|| Some(0xabcdef), || Some(1)), 0xabcdef);
+        assert_eq!(node_id(|| None, || Some(u64::MAX)), (1 << MAC_ADDRESS_BITS) - 1);
+        let hashed = node_id(|| None, || None);
+        assert_eq!(hashed, host_node_id());
+        assert!(hashed < 1 << MAC_ADDRESS_BITS);
+    }
+
     #[test]
     fn test_gen_id() {
         let id = gen_id();
         assert!(id > 0);
     }
 
+    #[test]
+    fn test_verify_id() {
+        let id = gen_id_checked();
+        assert!(verify_id(id));
+        for bit in 0..128 {
+            assert!(!verify_id(id ^ (1 << bit)));
+        }
+        for digit in 2..24 {
+            let shift = digit * 5;
+            let (a, b) = ((id >> shift) & 0x1f, (id >> (shift + 5)) & 0x1f);
+            if a != b {
+                assert!(!verify_id(id & !(0x3ff << shift) | (a << (shift + 5)) | (b << shift)));
+            }
+        }
+    }
+
     #[test]
     fn test_gen_id_concurrent() {
         let barrier = Arc::new(Barrier::new(1000));
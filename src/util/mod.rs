@@ -6,6 +6,8 @@ pub mod id_gen;
 pub mod env;
 #[cfg(feature = "etcd-client-sync")]
 pub mod etcd_client_sync;
+#[cfg(feature = "etcd")]
+pub mod etcd;
 
-#[cfg(any(feature = "tracing", feature = "http-server-tracer", feature = "http-client"))]
-pub(crate) mod radix32;
+#[cfg(any(feature = "tracing", feature = "http-server-tracer", feature = "http-client", feature = "id-gen"))]
+pub mod radix32;
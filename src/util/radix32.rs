@@ -1,20 +1,14 @@
-#[cfg(any(feature = "tracing", feature = "http-client"))]
 use std::fmt::Display;
-#[cfg(any(feature = "tracing", feature = "http-client"))]
 use std::fmt::Formatter;
-#[cfg(any(feature = "tracing", feature = "http-client"))]
 use std::str::from_utf8_unchecked;
 
-#[cfg(any(feature = "tracing", feature = "http-client"))]
 pub struct Radix32(u128);
 
-#[cfg(any(feature = "tracing", feature = "http-client"))]
 #[inline]
 pub fn radix_32(n: u128) -> Radix32 {
     Radix32(n)
 }
 
-#[cfg(any(feature = "tracing", feature = "http-client"))]
 impl Display for Radix32 {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         const MASK: u128 = (1 << 5) - 1;
@@ -31,7 +25,7 @@ impl Display for Radix32 {
                 }
                 _ => unreachable!("unreachable")
             }
-            n = n >> 5;
+            n >>= 5;
             if n == 0 {
                 index = i;
                 break;
@@ -42,19 +36,19 @@ impl Display for Radix32 {
     }
 }
 
-#[cfg(feature = "http-server-tracer")]
 pub fn from_radix_32(s: &str) -> Option<u128> {
-    if !matches!(s.len(), 1..=26) {
+    // 26 digits hold 130 bits, so the leading one of a full length id can only carry 3
+    if !matches!(s.len(), 1..=26) || (s.len() == 26 && s.as_bytes()[0] > b'7') {
         return None;
     }
     let mut n: u128 = 0;
     for &c in s.as_bytes().iter() {
-        n = n << 5;
+        n <<= 5;
         match c {
             b'0'..=b'9' => {
                 n |= (c - b'0') as u128
             }
-            b'a'..=b'z' => {
+            b'a'..=b'v' => {
                 n |= (c - b'a' + 10) as u128
             }
             _ => return None
@@ -62,3 +56,99 @@ pub fn from_radix_32(s: &str) -> Option<u128> {
     }
     Some(n)
 }
+
+const CROCKFORD_DIGITS: &[u8; 32] = b"0123456789abcdefghjkmnpqrstvwxyz";
+const CROCKFORD_CHECK_SYMBOLS: &[u8; 5] = b"*~$=u";
+
+/// Crockford's base 32, which leaves out `i`, `l`, `o` and `u` so ids read back by humans are unambiguous.
+pub struct Crockford {
+    n: u128,
+    check: bool,
+}
+
+/// Formats `n` in lowercase Crockford base 32, followed by its mod 37 check symbol when `check` is set.
+pub fn radix_32_crockford(n: u128, check: bool) -> Crockford {
+    Crockford { n, check }
+}
+
+impl Display for Crockford {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut n = self.n;
+        let mut buf = [0_u8; 27];
+        let mut index = 26;
+        loop {
+            index -= 1;
+            buf[index] = CROCKFORD_DIGITS[(n & 0x1f) as usize];
+            n >>= 5;
+            if n == 0 {
+                break;
+            }
+        }
+        let mut end = 26;
+        if self.check {
+            buf[end] = check_symbol((self.n % 37) as usize);
+            end += 1;
+        }
+        f.write_str(unsafe { from_utf8_unchecked(&buf[index..end]) })
+    }
+}
+
+fn check_symbol(value: usize) -> u8 {
+    match value {
+        0..32 => CROCKFORD_DIGITS[value],
+        _ => CROCKFORD_CHECK_SYMBOLS[value - 32],
+    }
+}
+
+/// Parses Crockford base 32 case-insensitively, ignoring `-` and reading `o` as `0` and `i`/`l` as `1`.
+/// With `check` the last symbol must be the check symbol of the rest.
+pub fn from_radix_32_crockford(s: &str, check: bool) -> Option<u128> {
+    let mut digits = s.bytes()
+        .filter(|&c| c != b'-')
+        .map(|c| c.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let check_symbol = if check { Some(digits.pop()?) } else { None };
+    if !matches!(digits.len(), 1..=26) {
+        return None;
+    }
+
+    let mut n: u128 = 0;
+    for c in digits {
+        let d = match c {
+            b'o' => 0,
+            b'i' | b'l' => 1,
+            c => CROCKFORD_DIGITS.iter().position(|&d| d == c)? as u128,
+        };
+        if n.leading_zeros() < 5 {
+            return None;
+        }
+        n = (n << 5) | d;
+    }
+    match check_symbol {
+        Some(c) if c != self::check_symbol((n % 37) as usize) => None,
+        _ => Some(n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crockford_round_trip() {
+        for n in [0, 1, 36, 37, u64::MAX as u128, u128::MAX] {
+            for check in [false, true] {
+                assert_eq!(from_radix_32_crockford(&radix_32_crockford(n, check).to_string(), check), Some(n));
+            }
+        }
+    }
+
+    #[test]
+    fn test_crockford_decode() {
+        assert_eq!(radix_32_crockford(32 * 18 + 21, false).to_string(), "jn");
+        assert_eq!(from_radix_32_crockford("O-Il", false), Some(33));
+        assert_eq!(from_radix_32_crockford("u", false), None);
+        assert_eq!(from_radix_32_crockford("jnu", true), None);
+        assert_eq!(from_radix_32_crockford(&"z".repeat(26), false), None);
+    }
+}